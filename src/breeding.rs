@@ -0,0 +1,256 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    camera::window_to_world_position,
+    evolution::mutate,
+    preset::Preset,
+    resources::{actions::Action, rules::Rules, settings::Settings, world::WorldSize},
+    universe::{Universe, UniverseBundle, UniversePlugin, UNIVERSE_GAP},
+};
+
+// Particles per group in every universe unless the amounts are given, the grid runs many at once
+pub const BREEDING_AMOUNT: usize = 250;
+
+const BREEDING_MUTATION_RATE: f32 = 0.3;
+const BREEDING_MUTATION_STRENGTH: f32 = 0.3;
+
+// Leave some room around the grid
+const GRID_MARGIN: f32 = 1.05;
+
+// Small independent universes side by side, each running a mutation of the parent rules.
+// Clicking one makes it the parent of the next generation
+#[derive(Resource)]
+pub struct Breeding<const S: usize> {
+    pub parent: Rules<S>,
+    pub generation: usize,
+    pub settings: Settings,
+    pub world: WorldSize,
+    // Chosen parents are saved here as presets
+    pub directory: PathBuf,
+    count: usize,
+    rng: StdRng,
+}
+
+impl<const S: usize> Breeding<S> {
+    pub fn new(
+        parent: Rules<S>,
+        settings: Settings,
+        world: WorldSize,
+        count: usize,
+        directory: PathBuf,
+    ) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(parent.seed),
+            parent,
+            generation: 0,
+            settings,
+            world,
+            directory,
+            count,
+        }
+    }
+
+    // Rules of every universe of the generation. The first universe keeps the parent rules
+    // unchanged, so a good parent is never lost
+    fn offspring(&mut self) -> Vec<Rules<S>> {
+        (0..self.count)
+            .map(|index| {
+                let mut rules = self.parent.clone();
+                if index > 0 {
+                    rules.seed = self.rng.gen();
+                    mutate(
+                        &mut rules,
+                        BREEDING_MUTATION_RATE,
+                        BREEDING_MUTATION_STRENGTH,
+                        &mut self.rng,
+                    );
+                }
+                rules
+            })
+            .collect()
+    }
+
+    pub fn select(&mut self, index: usize, rules: Rules<S>) {
+        self.parent = rules;
+
+        let path = self
+            .directory
+            .join(format!("breeding-{:03}.json", self.generation));
+        match Preset::new(&self.parent, Some(&self.settings)).save(&path) {
+            Ok(()) => info!(
+                "Generation {}: picked universe {} (seed {}), saved to {}",
+                self.generation,
+                index,
+                self.parent.seed,
+                path.display()
+            ),
+            Err(err) => error!("Failed to save preset to {}: {}", path.display(), err),
+        }
+
+        self.generation += 1;
+    }
+
+    pub fn restart(&mut self) {
+        let amount = self.parent.amount;
+        self.parent = Rules::from_seed(self.rng.gen());
+        self.parent.amount = amount;
+        self.generation = 0;
+        info!("Breeding from new random rules (seed {})", self.parent.seed);
+    }
+
+    fn columns(&self) -> usize {
+        (self.count as f32).sqrt().ceil() as usize
+    }
+
+    fn rows(&self) -> usize {
+        self.count.div_ceil(self.columns())
+    }
+
+    fn cell(&self) -> Vec2 {
+        self.world.extent() + UNIVERSE_GAP
+    }
+
+    // Size of the whole grid of universes, centered on the origin
+    fn grid_size(&self) -> Vec2 {
+        Vec2::new(self.columns() as f32, self.rows() as f32) * self.cell() - UNIVERSE_GAP
+    }
+
+    // Center of the universe at `index`, filling the grid row by row from the top left
+    fn offset(&self, index: usize) -> Vec2 {
+        let column = (index % self.columns()) as f32;
+        let row = (index / self.columns()) as f32;
+        let cell = self.cell();
+        let first = (self.grid_size() - self.world.extent()) / 2.;
+        Vec2::new(column * cell.x - first.x, first.y - row * cell.y)
+    }
+}
+
+// Place of a universe in the breeding grid
+#[derive(Component)]
+struct BreedingUniverse(usize);
+
+pub struct BreedingPlugin<const S: usize>;
+
+impl<const S: usize> Plugin for BreedingPlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(UniversePlugin::<S>)
+            .add_startup_system(setup_breeding)
+            .add_system(breeding_controls::<S>.label("breeding_controls"))
+            .add_system(
+                spawn_generation::<S>
+                    .after("breeding_controls")
+                    .before("step_universes"),
+            )
+            .add_system(frame_grid::<S>);
+    }
+}
+
+fn setup_breeding(mut commands: Commands) {
+    commands.spawn(Camera2dBundle::default());
+}
+
+// Replaces the universes whenever a new generation starts, the first one included
+fn spawn_generation<const S: usize>(
+    mut commands: Commands,
+    mut breeding: ResMut<Breeding<S>>,
+    universe_query: Query<Entity, With<BreedingUniverse>>,
+) {
+    if !breeding.is_changed() {
+        return;
+    }
+
+    for entity in universe_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    // Drawing the offspring is no new generation
+    let offspring = breeding.bypass_change_detection().offspring();
+    let (settings, world) = (breeding.settings.clone(), breeding.world);
+    for (index, rules) in offspring.into_iter().enumerate() {
+        let universe = Universe::new(rules, settings.clone(), world);
+        commands
+            .spawn(UniverseBundle::new(universe, breeding.offset(index)))
+            .insert(BreedingUniverse(index))
+            .with_children(|parent| {
+                parent.spawn(SpriteBundle {
+                    sprite: Sprite {
+                        color: Color::rgb(0.05, 0.05, 0.05),
+                        custom_size: Some(world.extent()),
+                        ..default()
+                    },
+                    // Behind the particles, the 2D camera sees down to -0.1
+                    transform: Transform::from_xyz(0., 0., -0.05),
+                    ..default()
+                });
+            });
+    }
+}
+
+type BreedingUniverseQuery<'w, 's, const S: usize> = Query<
+    'w,
+    's,
+    (
+        &'static BreedingUniverse,
+        &'static Universe<S>,
+        &'static Transform,
+    ),
+>;
+
+fn breeding_controls<const S: usize>(
+    windows: Res<Windows>,
+    mut breeding: ResMut<Breeding<S>>,
+    action_query: Query<&ActionState<Action>>,
+    camera_query: Query<&Transform, With<Camera>>,
+    universe_query: BreedingUniverseQuery<S>,
+) {
+    let action_state = action_query.single();
+
+    if action_state.just_pressed(Action::Restart) {
+        breeding.restart();
+        return;
+    }
+
+    if !action_state.just_released(Action::CameraPan) {
+        return;
+    }
+    let Some((window, cursor)) = windows
+        .get_primary()
+        .and_then(|window| Some((window, window.cursor_position()?)))
+    else {
+        return;
+    };
+    let cursor = window_to_world_position(cursor, window, camera_query.single());
+
+    let bounds = breeding.world.bounds();
+    for (place, universe, transform) in universe_query.iter() {
+        let inside = (cursor - transform.translation.truncate())
+            .abs()
+            .cmple(bounds)
+            .all();
+        if inside {
+            breeding.select(place.0, universe.rules().clone());
+            return;
+        }
+    }
+}
+
+// Keeps the whole grid in view as the window changes
+fn frame_grid<const S: usize>(
+    windows: Res<Windows>,
+    breeding: Res<Breeding<S>>,
+    mut camera_query: Query<&mut Transform, With<Camera>>,
+) {
+    let Some(window) = windows.get_primary() else {
+        return;
+    };
+    let window_size = Vec2::new(window.width(), window.height()).max(Vec2::ONE);
+    let zoom = (breeding.grid_size() / window_size).max_element() * GRID_MARGIN;
+
+    for mut transform in camera_query.iter_mut() {
+        transform.scale = Vec3::new(zoom, zoom, 1.);
+    }
+}
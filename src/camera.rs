@@ -1,20 +1,141 @@
 use bevy::{input::mouse::MouseWheel, prelude::*};
-use leafwing_input_manager::{action_state, prelude::ActionState};
+use leafwing_input_manager::prelude::ActionState;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    resources::{actions::Action, rules::Rules},
-    SIZE,
+    clusters::Clusters,
+    entity::particle::{ParticleMarker, Position},
+    resources::{
+        actions::Action,
+        chunks::Chunks,
+        settings::{EdgeMode, Settings},
+        world::WorldSize,
+    },
 };
 
+// Scroll lines zoomed per second while a zoom key is held
+const KEYBOARD_ZOOM_SPEED: f32 = 15.;
+
+// Zoom factor of a single scroll line
+const ZOOM_STEP: f32 = 0.9;
+
+// Leave some room around whatever is being framed
+const FIT_MARGIN: f32 = 1.05;
+
+const BOOKMARK_ACTIONS: [Action; 9] = [
+    Action::CameraBookmark1,
+    Action::CameraBookmark2,
+    Action::CameraBookmark3,
+    Action::CameraBookmark4,
+    Action::CameraBookmark5,
+    Action::CameraBookmark6,
+    Action::CameraBookmark7,
+    Action::CameraBookmark8,
+    Action::CameraBookmark9,
+];
+
+// Screen pixels the cursor may move between press and release to still count as a click
+const CLICK_TOLERANCE: f32 = 4.;
+// Screen pixels around the cursor searched for a particle to follow
+const PICK_RADIUS: f32 = 12.;
+
 #[derive(Resource, Default)]
 pub struct MousePosition {
     pub world: Vec2,
     pub screen: Vec2,
+    // Screen movement since the last frame
+    pub delta: Vec2,
 }
 
+#[derive(Component)]
+pub struct MainCamera;
+
 #[derive(Resource)]
 pub struct CameraZoom(pub f32);
 
+#[derive(Resource, Deserialize, Clone)]
+#[serde(default)]
+pub struct CameraSettings {
+    pub min_zoom: f32,
+    pub max_zoom: f32,
+    // How quickly the camera catches up with its target, per second
+    pub smoothing: f32,
+    // Keyboard panning speed in screen pixels per second
+    pub pan_speed: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            min_zoom: 0.125,
+            max_zoom: 10.,
+            smoothing: 10.,
+            pan_speed: 960.,
+        }
+    }
+}
+
+impl CameraSettings {
+    pub fn clamp_zoom(&self, zoom: f32) -> f32 {
+        zoom.clamp(self.min_zoom, self.max_zoom)
+    }
+}
+
+// Where the main camera is heading, `camera_smoothing` eases towards it every frame
+#[derive(Resource)]
+pub struct CameraTarget {
+    pub translation: Vec2,
+    pub zoom: f32,
+}
+
+impl Default for CameraTarget {
+    fn default() -> Self {
+        Self {
+            translation: Vec2::ZERO,
+            zoom: 1.,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct CameraBookmark {
+    pub translation: [f32; 2],
+    pub zoom: f32,
+}
+
+// Camera positions stored with Ctrl+number and recalled with the number keys
+#[derive(Resource, Default, Clone)]
+pub struct CameraBookmarks(pub [Option<CameraBookmark>; 9]);
+
+impl CameraBookmarks {
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(Option::is_none)
+    }
+}
+
+impl From<&[Option<CameraBookmark>]> for CameraBookmarks {
+    fn from(bookmarks: &[Option<CameraBookmark>]) -> Self {
+        let mut slots = Self::default();
+        for (slot, bookmark) in slots.0.iter_mut().zip(bookmarks) {
+            *slot = *bookmark;
+        }
+        slots
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FollowMode {
+    Particle,
+    Cluster,
+}
+
+#[derive(Resource, Default)]
+pub struct CameraFollow {
+    pub target: Option<(Entity, FollowMode)>,
+    // Screen position the pan button went down at, to tell clicks from drags
+    press: Option<Vec2>,
+}
+
 impl Default for CameraZoom {
     fn default() -> Self {
         Self(1f32)
@@ -34,83 +155,330 @@ pub fn window_to_world_position(
     return pos.round();
 }
 
-// Slightly simpler constant rate zooming!
-//zoom = zoom*pow(targetZoom/zoom, deltaTime/duration)
-//
-//// ..or as a function:
-//float logerp(float a, float b, float t){
-//    return a*pow(b/a, t);
-//}
+pub fn cursor_movement(
+    mut move_events: EventReader<CursorMoved>,
+    windows: Res<Windows>,
+    mut mouse_position: ResMut<MousePosition>,
+    camera_query: Query<&Transform, With<MainCamera>>,
+) {
+    let previous = mouse_position.screen;
+    for event in move_events.iter() {
+        mouse_position.screen = event.position;
+    }
+    mouse_position.delta = mouse_position.screen - previous;
+
+    if let Some(window) = windows.get_primary() {
+        mouse_position.world =
+            window_to_world_position(mouse_position.screen, window, camera_query.single());
+    }
+}
 
 pub fn camera_movement(
-    mut move_events: EventReader<CursorMoved>,
     mut scroll_events: EventReader<MouseWheel>,
-    mut mouse_position: ResMut<MousePosition>,
-    mut camera_query: Query<(&mut Transform, &mut OrthographicProjection), With<Camera>>,
-    mut windows: Res<Windows>,
-    mut zoom: ResMut<CameraZoom>,
+    time: Res<Time>,
+    mouse_position: Res<MousePosition>,
+    settings: Res<CameraSettings>,
+    mut target: ResMut<CameraTarget>,
+    mut camera_query: Query<&mut Transform, With<MainCamera>>,
     action_query: Query<&ActionState<Action>>,
 ) {
     let action_state = action_query.single();
+    let mut camera_transform = camera_query.single_mut();
+    let dt = time.delta_seconds();
 
-    // Calculate camera zoom
+    // Calculate target zoom
     let mut zoom_delta = 0.;
     for event in scroll_events.iter() {
-        zoom_delta += event.y as f32;
+        zoom_delta += event.y;
+    }
+    if action_state.pressed(Action::CameraZoomIn) {
+        zoom_delta += KEYBOARD_ZOOM_SPEED * dt;
     }
-    zoom.0 = zoom.0 * (0.125 / zoom.0).powf((zoom_delta as f32) / 20.);
+    if action_state.pressed(Action::CameraZoomOut) {
+        zoom_delta -= KEYBOARD_ZOOM_SPEED * dt;
+    }
+    let old_zoom = target.zoom;
+    target.zoom = settings.clamp_zoom(target.zoom * ZOOM_STEP.powf(zoom_delta));
+
+    // Zoom around the cursor, so the world position under it ends up in the same place
+    let cursor = mouse_position.world;
+    target.translation = cursor + (target.translation - cursor) * (target.zoom / old_zoom);
 
-    let mut delta = Vec2::ZERO;
+    let zoom = camera_transform.scale.x;
 
-    let camera_pan = action_state.pressed(Action::CameraPan);
+    // Dragging moves the camera directly, so the world sticks to the cursor
+    if action_state.pressed(Action::CameraPan) {
+        let delta = mouse_position.delta * zoom;
+        camera_transform.translation -= delta.extend(0.);
+        target.translation -= delta;
+        return;
+    }
+
+    // Move camera with keyboard
+    let mut direction = Vec2::ZERO;
+    if action_state.pressed(Action::CameraRight) {
+        direction.x += 1.;
+    }
+    if action_state.pressed(Action::CameraLeft) {
+        direction.x -= 1.;
+    }
+    if action_state.pressed(Action::CameraUp) {
+        direction.y += 1.;
+    }
+    if action_state.pressed(Action::CameraDown) {
+        direction.y -= 1.;
+    }
 
+    // Speed modifier
+    let mut speed = settings.pan_speed;
+    if action_state.pressed(Action::CameraFasterSpeed) {
+        speed *= 2.;
+    }
+
+    target.translation += direction * speed * zoom * dt;
+}
+
+// Eases the main camera towards its target at a constant logarithmic rate, independent of frame rate
+pub fn camera_smoothing(
+    time: Res<Time>,
+    settings: Res<CameraSettings>,
+    target: Res<CameraTarget>,
+    mut zoom: ResMut<CameraZoom>,
+    mut camera_query: Query<(&mut Transform, &mut OrthographicProjection), With<MainCamera>>,
+) {
     let (mut camera_transform, mut camera_projection) = camera_query.single_mut();
+    let t = 1. - (-settings.smoothing * time.delta_seconds()).exp();
 
-    // Calculate new mouse positions
-    for event in move_events.iter() {
-        if camera_pan {
-            delta = event.position - mouse_position.screen;
-        }
-        mouse_position.screen = event.position;
+    // zoom = zoom * (target / zoom)^t, so every doubling of the zoom takes the same time
+    zoom.0 *= (target.zoom / zoom.0).powf(t);
+
+    let translation = camera_transform.translation.truncate();
+    let translation = translation + (target.translation - translation) * t;
+
+    camera_transform.translation.x = translation.x;
+    camera_transform.translation.y = translation.y;
+    camera_transform.scale = Vec3::splat(zoom.0);
+    camera_projection.far = 1000. / zoom.0;
+}
+
+pub fn camera_framing(
+    windows: Res<Windows>,
+    world: Res<WorldSize>,
+    settings: Res<CameraSettings>,
+    mut target: ResMut<CameraTarget>,
+    mut follow: ResMut<CameraFollow>,
+    action_query: Query<&ActionState<Action>>,
+    particle_query: Query<&Position, With<ParticleMarker>>,
+) {
+    let action_state = action_query.single();
+    let window = windows.primary();
+    let window_size = Vec2::new(window.width(), window.height());
+
+    if [
+        Action::CameraReset,
+        Action::CameraFitWorld,
+        Action::CameraFitParticles,
+    ]
+    .into_iter()
+    .any(|action| action_state.just_pressed(action))
+    {
+        follow.target = None;
     }
 
-    mouse_position.world =
-        window_to_world_position(mouse_position.screen, windows.primary(), &camera_transform);
+    if action_state.just_pressed(Action::CameraReset) {
+        *target = CameraTarget::default();
+    }
 
-    // Move camera with keyboard
-    if !camera_pan {
-        if action_state.pressed(Action::CameraRight) {
-            delta.x -= 1.;
-        }
-        if action_state.pressed(Action::CameraLeft) {
-            delta.x += 1.;
-        }
-        if action_state.pressed(Action::CameraUp) {
-            delta.y -= 1.;
-        }
-        if action_state.pressed(Action::CameraDown) {
-            delta.y += 1.;
+    let mut frame = |center: Vec2, size: Vec2| {
+        target.translation = center;
+        target.zoom = settings.clamp_zoom((size / window_size).max_element() * FIT_MARGIN);
+    };
+
+    if action_state.just_pressed(Action::CameraFitWorld) {
+        frame(Vec2::ZERO, world.extent());
+    }
+
+    if action_state.just_pressed(Action::CameraFitParticles) {
+        let bounds = particle_query
+            .iter()
+            .fold(None, |bounds, pos| match bounds {
+                None => Some((pos.0, pos.0)),
+                Some((min, max)) => Some((pos.0.min(min), pos.0.max(max))),
+            });
+
+        if let Some((min, max)) = bounds {
+            frame((min + max) / 2., (max - min).max(Vec2::ONE));
         }
+    }
+}
 
-        // Speed modifier
-        delta *= 16.;
+pub fn camera_bookmarks(
+    settings: Res<CameraSettings>,
+    mut bookmarks: ResMut<CameraBookmarks>,
+    mut target: ResMut<CameraTarget>,
+    mut follow: ResMut<CameraFollow>,
+    action_query: Query<&ActionState<Action>>,
+) {
+    let action_state = action_query.single();
+    let store = action_state.pressed(Action::CameraStoreBookmark);
+
+    for (index, action) in BOOKMARK_ACTIONS.into_iter().enumerate() {
+        if !action_state.just_pressed(action) {
+            continue;
+        }
 
-        if action_state.pressed(Action::CameraFasterSpeed) {
-            delta *= 2.;
+        if store {
+            bookmarks.0[index] = Some(CameraBookmark {
+                translation: target.translation.to_array(),
+                zoom: target.zoom,
+            });
+            info!("Stored camera bookmark {}", index + 1);
+        } else if let Some(bookmark) = bookmarks.0[index] {
+            target.translation = Vec2::from(bookmark.translation);
+            target.zoom = settings.clamp_zoom(bookmark.zoom);
+            follow.target = None;
         }
     }
+}
 
-    // Apply changes to camera
-    delta *= zoom.0;
-    camera_transform.translation -= Vec3::new(delta.x, delta.y, 0.0);
-    camera_transform.scale = Vec3::splat(zoom.0);
-    camera_projection.far = 1000. / zoom.0;
+// Shortest offset from `from` to `to`, going across the world edge when it wraps
+fn wrapped_offset(from: Vec2, to: Vec2, edge_mode: EdgeMode, world: &WorldSize) -> Vec2 {
+    let mut offset = to - from;
+    if edge_mode == EdgeMode::WRAP {
+        offset -= (offset / world.extent()).round() * world.extent();
+    }
+    offset
 }
 
-pub fn controls(mut commands: Commands, action_query: Query<&ActionState<Action>>) {
+// Center of mass of the detected cluster `root` is part of, or `root` itself outside of clusters
+fn cluster_center(
+    clusters: &Clusters,
+    root: Entity,
+    root_pos: Vec2,
+    edge_mode: EdgeMode,
+    world: &WorldSize,
+    particle_query: &Query<&Position, With<ParticleMarker>>,
+) -> Vec2 {
+    let Some(cluster) = clusters
+        .clusters
+        .iter()
+        .find(|cluster| cluster.members.contains(&root))
+    else {
+        return root_pos;
+    };
+
+    // Offsets are relative to the root, so a cluster spanning the world edge stays in one piece
+    let offsets: Vec2 = particle_query
+        .iter_many(&cluster.members)
+        .map(|pos| wrapped_offset(root_pos, pos.0, edge_mode, world))
+        .sum();
+    root_pos + offsets / cluster.size() as f32
+}
+
+pub fn follow_controls(
+    world: Res<WorldSize>,
+    mouse_position: Res<MousePosition>,
+    zoom: Res<CameraZoom>,
+    chunks: Option<Res<Chunks>>,
+    mut follow: ResMut<CameraFollow>,
+    action_query: Query<&ActionState<Action>>,
+) {
     let action_state = action_query.single();
 
-    if action_state.just_pressed(Action::Restart) {
-        commands.insert_resource(Rules::<SIZE>::random());
+    if action_state.just_pressed(Action::CameraPan) {
+        follow.press = Some(mouse_position.screen);
+    }
+
+    // Any manual movement takes the camera back from the follow target
+    let dragging = action_state.pressed(Action::CameraPan)
+        && follow
+            .press
+            .is_some_and(|press| press.distance(mouse_position.screen) > CLICK_TOLERANCE);
+    let moving = [
+        Action::CameraUp,
+        Action::CameraDown,
+        Action::CameraLeft,
+        Action::CameraRight,
+    ]
+    .into_iter()
+    .any(|action| action_state.pressed(action));
+    if dragging || moving {
+        follow.target = None;
+    }
+
+    if !action_state.just_released(Action::CameraPan) {
+        return;
+    }
+    let Some(press) = follow.press.take() else {
+        return;
+    };
+    if press.distance(mouse_position.screen) > CLICK_TOLERANCE {
+        return;
+    }
+
+    let mode = if action_state.pressed(Action::CameraFollowCluster) {
+        FollowMode::Cluster
+    } else {
+        FollowMode::Particle
+    };
+
+    // Clicking empty space lets go of the current target
+    let world_bounds = world.bounds();
+    follow.target = chunks.and_then(|chunks| {
+        chunks
+            .nearest_particle(
+                mouse_position.world.clamp(-world_bounds, world_bounds),
+                PICK_RADIUS * zoom.0,
+            )
+            .map(|(entity, _, _)| (entity, mode))
+    });
+}
+
+pub fn camera_follow(
+    settings: Res<Settings>,
+    world: Res<WorldSize>,
+    clusters: Res<Clusters>,
+    mut follow: ResMut<CameraFollow>,
+    mut camera_target: ResMut<CameraTarget>,
+    mut camera_query: Query<&mut Transform, With<MainCamera>>,
+    particle_query: Query<&Position, With<ParticleMarker>>,
+) {
+    let Some((entity, mode)) = follow.target else {
+        return;
+    };
+    let Ok(pos) = particle_query.get(entity) else {
+        // The particle is gone, e.g. after a restart
+        follow.target = None;
+        return;
+    };
+
+    let target = match mode {
+        FollowMode::Cluster => cluster_center(
+            &clusters,
+            entity,
+            pos.0,
+            settings.edge_mode,
+            &world,
+            &particle_query,
+        ),
+        FollowMode::Particle => pos.0,
+    };
+
+    let mut camera_transform = camera_query.single_mut();
+    let camera = camera_transform.translation.truncate();
+
+    // Head for the closest copy of the target, the camera smoothing does the damping
+    camera_target.translation = camera + wrapped_offset(camera, target, settings.edge_mode, &world);
+
+    // Follow the target across the world edge instead of panning back over the whole world
+    if settings.edge_mode == EdgeMode::WRAP {
+        let bounds = world.bounds();
+        let wrapped = Vec2::new(
+            (camera.x + bounds.x).rem_euclid(bounds.x * 2.) - bounds.x,
+            (camera.y + bounds.y).rem_euclid(bounds.y * 2.) - bounds.y,
+        );
+        let shift = wrapped - camera;
+        camera_transform.translation += shift.extend(0.);
+        camera_target.translation += shift;
     }
 }
@@ -0,0 +1,666 @@
+use std::{
+    fs,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::{
+    core_pipeline::bloom::BloomSettings,
+    prelude::*,
+    render::{
+        camera::RenderTarget,
+        main_graph::node::CAMERA_DRIVER,
+        render_asset::{PrepareAssetLabel, RenderAssets},
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext},
+        render_resource::{
+            Buffer, BufferDescriptor, BufferUsages, Extent3d, ImageCopyBuffer, ImageDataLayout,
+            MapMode, TextureDimension, TextureFormat, TextureUsages,
+        },
+        renderer::{RenderContext, RenderDevice},
+        Extract, RenderApp, RenderStage,
+    },
+    tasks::IoTaskPool,
+};
+use crossbeam_channel::{Receiver, Sender};
+use image::RgbaImage;
+use leafwing_input_manager::prelude::ActionState;
+use serde::Deserialize;
+
+use crate::{
+    camera::MainCamera,
+    entity::particle::{GroupId, ParticleMarker, Position},
+    resources::{actions::Action, rules::Rules, tick::Tick, world::WorldSize},
+};
+
+const CAPTURE_NODE: &str = "capture";
+
+// Largest texture side most GPUs will allocate
+pub const MAX_CAPTURE_SIZE: u32 = 8192;
+
+// Frames to let a freshly spawned capture camera render before reading it back, so pipelines
+// specialized for the new target have been compiled
+pub const CAPTURE_WARMUP_FRAMES: u8 = 3;
+
+#[derive(Resource, Deserialize, Clone)]
+#[serde(default)]
+pub struct ScreenshotSettings {
+    pub directory: PathBuf,
+    pub poster_scale: u32,
+}
+
+impl Default for ScreenshotSettings {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("screenshots"),
+            poster_scale: 4,
+        }
+    }
+}
+
+pub struct ScreenshotEvent {
+    pub scale: u32,
+}
+
+// Ticks to take a screenshot at without a key press, for runs without a window
+#[derive(Resource, Default)]
+pub struct ScheduledScreenshots(pub Vec<u64>);
+
+#[derive(Component)]
+pub struct PendingCapture {
+    path: PathBuf,
+    frames: u8,
+}
+
+// Persistent capture camera that is read back whenever `frame` is set
+#[derive(Component, Default)]
+pub struct FrameCapture {
+    pub frame: Option<u64>,
+}
+
+pub struct FrameCaptured {
+    pub frame: u64,
+    pub image: RgbaImage,
+}
+
+#[derive(Clone, Copy)]
+enum CaptureOutput {
+    Screenshot,
+    Frame(u64),
+}
+
+pub type MainCameraQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static Transform,
+        &'static OrthographicProjection,
+        &'static Camera2d,
+        Option<&'static BloomSettings>,
+    ),
+    With<MainCamera>,
+>;
+
+#[derive(Resource)]
+struct CaptureSender(Sender<CapturedFrame>);
+
+#[derive(Resource)]
+struct CaptureReceiver(Receiver<CapturedFrame>);
+
+struct CapturedFrame {
+    entity: Entity,
+    output: CaptureOutput,
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+#[derive(Clone)]
+struct CaptureJob {
+    entity: Entity,
+    image: Handle<Image>,
+    output: CaptureOutput,
+}
+
+#[derive(Resource, Default)]
+struct CaptureJobs(Vec<CaptureJob>);
+
+struct CaptureBuffer {
+    entity: Entity,
+    output: CaptureOutput,
+    image: Handle<Image>,
+    buffer: Buffer,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+}
+
+#[derive(Resource, Default)]
+struct CaptureBuffers(Vec<CaptureBuffer>);
+
+pub struct CapturePlugin<const S: usize>;
+
+impl<const S: usize> Plugin for CapturePlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ScreenshotSettings>()
+            .init_resource::<ScheduledScreenshots>()
+            .add_event::<ScreenshotEvent>()
+            .add_event::<FrameCaptured>()
+            .add_system(screenshot_controls.before("capture"))
+            .add_system(scheduled_screenshots.after("tick").before("capture"));
+
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            // Without a renderer, screenshots are rasterized on the CPU from particle positions
+            app.add_system(cpu_capture::<S>.label("capture"));
+            return;
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        render_app
+            .insert_resource(CaptureSender(sender))
+            .init_resource::<CaptureJobs>()
+            .init_resource::<CaptureBuffers>()
+            .add_system_to_stage(RenderStage::Extract, extract_captures)
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                prepare_capture_buffers.after(PrepareAssetLabel::AssetPrepare),
+            )
+            .add_system_to_stage(RenderStage::Cleanup, read_capture_buffers);
+
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        graph.add_node(CAPTURE_NODE, CaptureNode);
+        graph.add_node_edge(CAMERA_DRIVER, CAPTURE_NODE).unwrap();
+
+        app.insert_resource(CaptureReceiver(receiver))
+            .add_system(start_gpu_capture::<S>.label("capture"))
+            .add_system(save_gpu_captures);
+    }
+}
+
+pub fn capture_name(seed: u64) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis())
+        .unwrap_or_default();
+
+    format!("particlelife-{}-{:016x}", timestamp, seed)
+}
+
+pub fn screenshot_path(directory: &Path, seed: u64) -> PathBuf {
+    directory.join(format!("{}.png", capture_name(seed)))
+}
+
+// Saves in the background, so the frame doesn't wait for the encoder
+pub fn save_png(path: PathBuf, image: RgbaImage) {
+    IoTaskPool::get()
+        .spawn(async move { write_png(&path, &image) })
+        .detach();
+}
+
+// Saves right away, for runs that may exit before a background save finished
+pub fn write_png(path: &Path, image: &RgbaImage) {
+    if let Some(directory) = path.parent() {
+        if let Err(err) = fs::create_dir_all(directory) {
+            error!("Failed to create {}: {}", directory.display(), err);
+            return;
+        }
+    }
+
+    match image.save(path) {
+        Ok(()) => debug!("Saved {}", path.display()),
+        Err(err) => error!("Failed to save {}: {}", path.display(), err),
+    }
+}
+
+// Size of an image `scale` times as large as `width` by `height`, shrunk evenly on both sides
+// when it would exceed the largest texture, and the scale it ends up with
+pub fn capture_size(width: u32, height: u32, scale: u32) -> (u32, u32, f32) {
+    let scaled = Vec2::new(width as f32, height as f32) * scale as f32;
+    let fit = (MAX_CAPTURE_SIZE as f32 / scaled.x)
+        .min(MAX_CAPTURE_SIZE as f32 / scaled.y)
+        .min(1.0);
+    let size = (scaled * fit).floor();
+    (size.x as u32, size.y as u32, scale as f32 * fit)
+}
+
+pub fn capture_image(images: &mut Assets<Image>, width: u32, height: u32) -> Handle<Image> {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 255],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING
+        | TextureUsages::COPY_SRC
+        | TextureUsages::COPY_DST
+        | TextureUsages::RENDER_ATTACHMENT;
+    images.add(image)
+}
+
+pub fn capture_camera_bundle(
+    image: Handle<Image>,
+    transform: Transform,
+    far: f32,
+    camera_2d: &Camera2d,
+) -> (Camera2dBundle, UiCameraConfig) {
+    (
+        Camera2dBundle {
+            transform,
+            projection: OrthographicProjection { far, ..default() },
+            camera_2d: camera_2d.clone(),
+            camera: Camera {
+                hdr: true,
+                priority: -1,
+                target: RenderTarget::Image(image),
+                ..default()
+            },
+            ..default()
+        },
+        // Overlays like the minimap stay out of captures
+        UiCameraConfig { show_ui: false },
+    )
+}
+
+// Rasterizes particles into an image, approximating bloom with an additive glow around each
+// particle. `view_min` is the world position of the bottom left corner of the image and
+// `world_per_pixel` how many world units a single pixel covers
+pub fn rasterize(
+    particles: impl Iterator<Item = (Vec2, Color)>,
+    view_min: Vec2,
+    world_per_pixel: f32,
+    width: u32,
+    height: u32,
+    background: Color,
+) -> RgbaImage {
+    let [bg_r, bg_g, bg_b, _] = background.as_linear_rgba_f32();
+    let mut buffer = vec![[bg_r, bg_g, bg_b]; (width * height) as usize];
+
+    let radius = (2.0 / world_per_pixel).max(0.5);
+    let glow_radius = radius * 4.0;
+
+    for (pos, color) in particles {
+        let [r, g, b, _] = color.as_linear_rgba_f32();
+        let px = (pos.x - view_min.x) / world_per_pixel;
+        let py = height as f32 - (pos.y - view_min.y) / world_per_pixel;
+
+        let min_x = (px - glow_radius).floor().max(0.0) as u32;
+        let max_x = (px + glow_radius).ceil().min(width as f32) as u32;
+        let min_y = (py - glow_radius).floor().max(0.0) as u32;
+        let max_y = (py + glow_radius).ceil().min(height as f32) as u32;
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let dist = Vec2::new(x as f32 + 0.5 - px, y as f32 + 0.5 - py).length();
+                if dist > glow_radius {
+                    continue;
+                }
+
+                let core = (radius + 0.5 - dist).clamp(0.0, 1.0);
+                let glow = 0.25 * (-(dist / radius).powi(2) / 4.0).exp();
+                let intensity = core + glow;
+
+                let pixel = &mut buffer[(x + y * width) as usize];
+                pixel[0] += r * intensity;
+                pixel[1] += g * intensity;
+                pixel[2] += b * intensity;
+            }
+        }
+    }
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let [r, g, b] = buffer[(x + y * width) as usize];
+        let [r, g, b, _] =
+            Color::rgba_linear(r.min(1.0), g.min(1.0), b.min(1.0), 1.0).as_rgba_f32();
+        image::Rgba([
+            (r * 255.0).round() as u8,
+            (g * 255.0).round() as u8,
+            (b * 255.0).round() as u8,
+            255,
+        ])
+    })
+}
+
+pub fn screenshot_controls(
+    settings: Res<ScreenshotSettings>,
+    mut screenshot_events: EventWriter<ScreenshotEvent>,
+    action_query: Query<&ActionState<Action>>,
+) {
+    let action_state = action_query.single();
+
+    if action_state.just_pressed(Action::Screenshot) {
+        screenshot_events.send(ScreenshotEvent { scale: 1 });
+    }
+    if action_state.just_pressed(Action::PosterScreenshot) {
+        screenshot_events.send(ScreenshotEvent {
+            scale: settings.poster_scale.max(1),
+        });
+    }
+}
+
+fn scheduled_screenshots(
+    tick: Res<Tick>,
+    mut scheduled: ResMut<ScheduledScreenshots>,
+    mut screenshot_events: EventWriter<ScreenshotEvent>,
+) {
+    if scheduled.0.contains(&tick.0) {
+        scheduled.0.retain(|at| *at != tick.0);
+        screenshot_events.send(ScreenshotEvent { scale: 1 });
+    }
+}
+
+pub fn cpu_capture<const S: usize>(
+    rules: Res<Rules<S>>,
+    world: Res<WorldSize>,
+    (settings, clear_color): (Res<ScreenshotSettings>, Res<ClearColor>),
+    mut screenshot_events: EventReader<ScreenshotEvent>,
+    particle_query: Query<(&Position, &GroupId), With<ParticleMarker>>,
+) {
+    for event in screenshot_events.iter() {
+        let (width, height, scale) = capture_size(
+            (world.width * 2) as u32,
+            (world.height * 2) as u32,
+            event.scale,
+        );
+        let world_per_pixel = 1.0 / scale;
+
+        let image = rasterize(
+            particle_query
+                .iter()
+                .map(|(pos, id)| (pos.0, rules.colors[id.0])),
+            -world.bounds(),
+            world_per_pixel,
+            width,
+            height,
+            clear_color.0,
+        );
+
+        // Without a window this is likely a short run, which may exit right after
+        let path = screenshot_path(&settings.directory, rules.seed);
+        info!("Saving screenshot to {}", path.display());
+        write_png(&path, &image);
+    }
+}
+
+fn start_gpu_capture<const S: usize>(
+    mut commands: Commands,
+    rules: Res<Rules<S>>,
+    settings: Res<ScreenshotSettings>,
+    windows: Res<Windows>,
+    mut images: ResMut<Assets<Image>>,
+    mut screenshot_events: EventReader<ScreenshotEvent>,
+    camera_query: MainCameraQuery,
+) {
+    let Some(window) = windows.get_primary() else {
+        return;
+    };
+
+    for event in screenshot_events.iter() {
+        let (transform, projection, camera_2d, bloom) = camera_query.single();
+
+        let (width, height, scale) = capture_size(
+            window.physical_width(),
+            window.physical_height(),
+            event.scale,
+        );
+        if width == 0 || height == 0 {
+            continue;
+        }
+
+        let image = capture_image(&mut images, width, height);
+
+        // Keep the same world extents as the window, just with more pixels
+        let mut capture_transform = *transform;
+        capture_transform.scale = transform.scale / scale;
+
+        let mut capture = commands.spawn(capture_camera_bundle(
+            image,
+            capture_transform,
+            projection.far,
+            camera_2d,
+        ));
+        capture.insert(PendingCapture {
+            path: screenshot_path(&settings.directory, rules.seed),
+            frames: CAPTURE_WARMUP_FRAMES,
+        });
+        if let Some(bloom) = bloom {
+            capture.insert(bloom.clone());
+        }
+    }
+}
+
+fn save_gpu_captures(
+    mut commands: Commands,
+    receiver: Res<CaptureReceiver>,
+    mut images: ResMut<Assets<Image>>,
+    mut frame_events: EventWriter<FrameCaptured>,
+    mut capture_query: Query<(&Camera, &mut PendingCapture)>,
+) {
+    for (_, mut capture) in capture_query.iter_mut() {
+        capture.frames = capture.frames.saturating_sub(1);
+    }
+
+    for captured in receiver.0.try_iter() {
+        let Some(image) = RgbaImage::from_raw(captured.width, captured.height, captured.data)
+        else {
+            error!("Captured frame has an unexpected size");
+            continue;
+        };
+
+        match captured.output {
+            CaptureOutput::Screenshot => {
+                let Ok((camera, capture)) = capture_query.get(captured.entity) else {
+                    continue;
+                };
+
+                if let RenderTarget::Image(image) = &camera.target {
+                    images.remove(image);
+                }
+                commands.entity(captured.entity).despawn();
+
+                info!("Saving screenshot to {}", capture.path.display());
+                save_png(capture.path.clone(), image);
+            }
+            CaptureOutput::Frame(frame) => frame_events.send(FrameCaptured { frame, image }),
+        }
+    }
+}
+
+fn extract_captures(
+    mut jobs: ResMut<CaptureJobs>,
+    capture_query: Extract<Query<(Entity, &Camera, &PendingCapture)>>,
+    frame_query: Extract<Query<(Entity, &Camera, &FrameCapture)>>,
+) {
+    jobs.0.clear();
+    for (entity, camera, capture) in capture_query.iter() {
+        if capture.frames > 0 {
+            continue;
+        }
+        if let RenderTarget::Image(image) = &camera.target {
+            jobs.0.push(CaptureJob {
+                entity,
+                image: image.clone(),
+                output: CaptureOutput::Screenshot,
+            });
+        }
+    }
+    for (entity, camera, capture) in frame_query.iter() {
+        let Some(frame) = capture.frame else {
+            continue;
+        };
+        if let RenderTarget::Image(image) = &camera.target {
+            jobs.0.push(CaptureJob {
+                entity,
+                image: image.clone(),
+                output: CaptureOutput::Frame(frame),
+            });
+        }
+    }
+}
+
+fn prepare_capture_buffers(
+    jobs: Res<CaptureJobs>,
+    mut buffers: ResMut<CaptureBuffers>,
+    render_device: Res<RenderDevice>,
+    gpu_images: Res<RenderAssets<Image>>,
+) {
+    buffers.0.clear();
+    for job in jobs.0.iter() {
+        let Some(gpu_image) = gpu_images.get(&job.image) else {
+            continue;
+        };
+
+        let width = gpu_image.size.x as u32;
+        let height = gpu_image.size.y as u32;
+        let padded_bytes_per_row = RenderDevice::align_copy_bytes_per_row(width as usize * 4);
+
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("capture_buffer"),
+            size: (padded_bytes_per_row * height as usize) as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        buffers.0.push(CaptureBuffer {
+            entity: job.entity,
+            output: job.output,
+            image: job.image.clone(),
+            buffer,
+            width,
+            height,
+            padded_bytes_per_row: padded_bytes_per_row as u32,
+        });
+    }
+}
+
+struct CaptureNode;
+
+impl Node for CaptureNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let buffers = world.resource::<CaptureBuffers>();
+        let gpu_images = world.resource::<RenderAssets<Image>>();
+
+        for capture in buffers.0.iter() {
+            let Some(gpu_image) = gpu_images.get(&capture.image) else {
+                continue;
+            };
+
+            render_context.command_encoder.copy_texture_to_buffer(
+                gpu_image.texture.as_image_copy(),
+                ImageCopyBuffer {
+                    buffer: &capture.buffer,
+                    layout: ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: NonZeroU32::new(capture.padded_bytes_per_row),
+                        rows_per_image: None,
+                    },
+                },
+                Extent3d {
+                    width: capture.width,
+                    height: capture.height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn read_capture_buffers(
+    mut buffers: ResMut<CaptureBuffers>,
+    sender: Res<CaptureSender>,
+    render_device: Res<RenderDevice>,
+) {
+    for capture in buffers.0.drain(..) {
+        let slice = capture.buffer.slice(..);
+        render_device.map_buffer(&slice, MapMode::Read, |result| {
+            if let Err(err) = result {
+                error!("Failed to map capture buffer: {}", err);
+            }
+        });
+        render_device.poll(wgpu::Maintain::Wait);
+
+        let row_bytes = capture.width as usize * 4;
+        let mut data = Vec::with_capacity(row_bytes * capture.height as usize);
+        {
+            let mapped = slice.get_mapped_range();
+            for row in mapped.chunks(capture.padded_bytes_per_row as usize) {
+                data.extend_from_slice(&row[..row_bytes]);
+            }
+        }
+        capture.buffer.unmap();
+
+        let _ = sender.0.send(CapturedFrame {
+            entity: capture.entity,
+            output: capture.output,
+            width: capture.width,
+            height: capture.height,
+            data,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_size_scales_up_to_the_largest_texture() {
+        assert_eq!(capture_size(800, 600, 2), (1600, 1200, 2.0));
+
+        let (width, height, scale) = capture_size(4000, 2000, 4);
+        assert_eq!((width, height), (MAX_CAPTURE_SIZE, MAX_CAPTURE_SIZE / 2));
+        assert_eq!(scale, 4.0 * MAX_CAPTURE_SIZE as f32 / 16000.);
+    }
+
+    #[test]
+    fn empty_view_is_the_background() {
+        let image = rasterize(std::iter::empty(), Vec2::ZERO, 1., 4, 3, Color::BLACK);
+        assert_eq!(image.dimensions(), (4, 3));
+        assert!(image.pixels().all(|pixel| pixel.0 == [0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn particles_land_where_they_are_with_y_up() {
+        // A world of 40 by 40 around the origin, drawn 1 to 1
+        let view_min = Vec2::splat(-20.);
+        let particles = [(Vec2::new(-10., 10.), Color::RED)];
+        let image = rasterize(particles.into_iter(), view_min, 1., 40, 40, Color::BLACK);
+
+        // Upper left quarter, rows count down from the top
+        assert_eq!(image.get_pixel(10, 10).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(10, 30).0, [0, 0, 0, 255]);
+        assert_eq!(image.get_pixel(30, 10).0, [0, 0, 0, 255]);
+        // The glow fades out around it
+        let glow = image.get_pixel(15, 10).0;
+        assert!(glow[0] > 0 && glow[0] < 255 && glow[1] == 0);
+    }
+
+    #[test]
+    fn overlapping_particles_saturate() {
+        let particles = (0..10).map(|_| (Vec2::ZERO, Color::rgb(0.5, 0.5, 0.5)));
+        let image = rasterize(particles, Vec2::splat(-5.), 1., 10, 10, Color::BLACK);
+        assert_eq!(image.get_pixel(5, 5).0, [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn particles_outside_only_glow_into_the_edge() {
+        let particles = [
+            (Vec2::new(-1., 5.), Color::WHITE),
+            (Vec2::new(100., 5.), Color::WHITE),
+        ];
+        let image = rasterize(particles.into_iter(), Vec2::ZERO, 1., 10, 10, Color::BLACK);
+        assert!(image.get_pixel(0, 5).0[0] > 0);
+        assert_eq!(image.get_pixel(9, 5).0, [0, 0, 0, 255]);
+    }
+}
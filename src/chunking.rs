@@ -1,16 +1,22 @@
-use bevy::prelude::{ Query, ResMut,  With};
+use bevy::prelude::{Entity, Query, Res, ResMut, With};
 
 use crate::{
     entity::particle::{GroupId, ParticleMarker, Position},
-    resources::chunks::Chunks,
+    resources::{chunks::Chunks, settings::Settings},
 };
 
 pub fn sort_into_chunks(
     mut chunks: ResMut<Chunks>,
-    particle_query: Query<(&Position, &GroupId), With<ParticleMarker>>,
+    settings: Res<Settings>,
+    particle_query: Query<(Entity, &Position, &GroupId), With<ParticleMarker>>,
 ) {
     chunks.clear();
-    for (pos, id) in particle_query.iter() {
-        chunks.insert_particle(*pos, *id);
+    for (entity, pos, id) in particle_query.iter() {
+        chunks.insert_particle(entity, *pos, *id);
+    }
+
+    // Query order depends on entity layout, so sort to make force summation order reproducible
+    if settings.deterministic {
+        chunks.sort();
     }
 }
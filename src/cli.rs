@@ -0,0 +1,533 @@
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+use crate::{
+    config::CONFIG_PATH,
+    conversion::ConversionKind,
+    evolution::Fitness,
+    recording::RecordingSettings,
+    resources::{
+        settings::{EdgeMode, ForceSource, Integrator, Settings},
+        world::WorldSize,
+    },
+};
+
+// Group counts the simulation is compiled for
+pub const MAX_GROUPS: usize = 8;
+
+pub const DEFAULT_GROUPS: usize = 4;
+
+/// Particle Life: groups of particles attracting and repelling each other
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Number of particle groups, 4 unless a loaded snapshot, replay or preset brings its own
+    #[arg(long, value_parser = parse_groups)]
+    pub groups: Option<usize>,
+
+    /// Particles per group, either one value for every group or one value per group
+    #[arg(long, value_name = "N[,N...]", value_delimiter = ',')]
+    pub amount: Vec<usize>,
+
+    /// Half the width of the world, particles live between -width and width [default: 800]
+    #[arg(long)]
+    pub world_width: Option<usize>,
+
+    /// Half the height of the world, particles live between -height and height [default: 600]
+    #[arg(long)]
+    pub world_height: Option<usize>,
+
+    /// Seed for the random rules and starting positions
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Start from the rules in a preset file instead of random ones
+    #[arg(long, value_name = "FILE", conflicts_with = "seed")]
+    pub rules: Option<PathBuf>,
+
+    /// Restore a saved snapshot
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["rules", "replay"])]
+    pub snapshot: Option<PathBuf>,
+
+    /// Play back a recorded replay
+    #[arg(long, value_name = "FILE", conflicts_with = "rules")]
+    pub replay: Option<PathBuf>,
+
+    /// Strength of all attractions and repulsions
+    #[arg(long)]
+    pub g: Option<f32>,
+
+    /// Mass of every particle
+    #[arg(long)]
+    pub mass: Option<f32>,
+
+    /// Fraction of its velocity a particle keeps every step
+    #[arg(long)]
+    pub drag_coef: Option<f32>,
+
+    /// Distance beyond which particles don't interact
+    #[arg(long)]
+    pub max_dist: Option<f32>,
+
+    /// Distance a particle may move in a single step
+    #[arg(long)]
+    pub max_velocity: Option<f32>,
+
+    /// Strength of the random thermal kicks every particle gets each step
+    #[arg(long)]
+    pub temperature: Option<f32>,
+
+    /// Temperature of every group relative to --temperature, 1 for groups left out
+    #[arg(long, value_name = "T[,T...]", value_delimiter = ',')]
+    pub group_temperatures: Vec<f32>,
+
+    /// Fraction of the temperature lost every step
+    #[arg(long, value_name = "RATE")]
+    pub cooling_rate: Option<f32>,
+
+    /// Temperature cooling stops at
+    #[arg(long)]
+    pub min_temperature: Option<f32>,
+
+    /// Largest total force on a particle in a single step
+    #[arg(long)]
+    pub max_force: Option<f32>,
+
+    /// What happens to particles reaching the edge of the world
+    #[arg(long, value_enum)]
+    pub edge_mode: Option<EdgeMode>,
+
+    /// Distance from the edges within which particles are pushed back
+    #[arg(long)]
+    pub wall_range: Option<f32>,
+
+    /// Push of the walls right at the edges
+    #[arg(long)]
+    pub wall_strength: Option<f32>,
+
+    /// Sources of force to leave out
+    #[arg(
+        long,
+        value_enum,
+        value_name = "SOURCE[,SOURCE...]",
+        value_delimiter = ','
+    )]
+    pub disable_forces: Vec<ForceSource>,
+
+    /// How forces move the particles
+    #[arg(long, value_enum)]
+    pub integrator: Option<Integrator>,
+
+    /// Fixed iteration and summation order, so runs can be replayed exactly
+    #[arg(long)]
+    pub deterministic: bool,
+
+    /// Configuration file, reloaded whenever it changes
+    #[arg(long, value_name = "FILE", default_value = CONFIG_PATH)]
+    pub config: PathBuf,
+
+    /// Run the simulation without a window
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Exit after this many simulation steps
+    #[arg(long)]
+    pub steps: Option<u64>,
+
+    /// Save a snapshot of the final state when exiting after --steps
+    #[arg(long, value_name = "FILE", requires = "steps")]
+    pub output: Option<PathBuf>,
+
+    /// Save a screenshot when the simulation reaches these steps
+    #[arg(long, value_name = "STEP[,STEP...]", value_delimiter = ',')]
+    pub screenshot_at: Vec<u64>,
+
+    /// Record the first N simulation steps
+    #[arg(long, value_name = "N")]
+    pub record: Option<u64>,
+
+    /// Simulation steps between two recorded frames
+    #[arg(long, value_name = "N")]
+    pub record_interval: Option<u64>,
+
+    /// Size of recorded frames
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_size)]
+    pub record_size: Option<(u32, u32)>,
+
+    /// Simulation steps every rule set runs for when searching or evolving rules
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    pub eval_ticks: u64,
+
+    /// Score this many random rule sets without a window and save the best as presets
+    #[arg(long, value_name = "N", conflicts_with_all = ["snapshot", "replay", "rules"])]
+    pub search: Option<usize>,
+
+    /// Number of best rule sets a search saves
+    #[arg(long, value_name = "K", default_value_t = 5, requires = "search")]
+    pub top: usize,
+
+    /// Directory a search saves its presets to
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = "presets",
+        requires = "search"
+    )]
+    pub presets: PathBuf,
+
+    /// Evolve rule sets for this many generations without a window
+    #[arg(long, value_name = "GENERATIONS", conflicts_with_all = ["snapshot", "replay", "rules", "search"])]
+    pub evolve: Option<usize>,
+
+    /// Rule sets in every generation
+    #[arg(long, value_name = "N", default_value_t = 24, requires = "evolve")]
+    pub population: usize,
+
+    /// Best rule sets carried over unchanged into the next generation
+    #[arg(long, value_name = "N", default_value_t = 2, requires = "evolve")]
+    pub elite: usize,
+
+    /// What makes a rule set fit
+    #[arg(long, value_enum, default_value_t = Fitness::Interesting, requires = "evolve")]
+    pub fitness: Fitness,
+
+    /// Length divided by width of the largest cluster the shape fitness aims for
+    #[arg(long, value_name = "RATIO", default_value_t = 3., requires = "evolve")]
+    pub target_aspect: f32,
+
+    /// Chance of every attraction and repulsion of a child to be perturbed
+    #[arg(long, value_name = "P", default_value_t = 0.1, requires = "evolve")]
+    pub mutation_rate: f32,
+
+    /// Largest perturbation of a mutated attraction
+    #[arg(long, value_name = "X", default_value_t = 0.2, requires = "evolve")]
+    pub mutation_strength: f32,
+
+    /// Directory evolution writes its lineage and best rule sets to
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = "evolution",
+        requires = "evolve"
+    )]
+    pub evolution_dir: PathBuf,
+
+    /// Breed rules by picking the best of this many mutated universes shown side by side
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = parse_universes,
+        conflicts_with_all = ["snapshot", "replay", "headless", "search", "evolve"]
+    )]
+    pub breed: Option<usize>,
+
+    /// Directory breeding saves the picked rules to
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = "presets",
+        requires = "breed"
+    )]
+    pub breeding_dir: PathBuf,
+
+    /// Let particles feed on each other, die without energy and divide with plenty of it
+    #[arg(long, conflicts_with_all = ["search", "evolve", "breed"])]
+    pub ecology: bool,
+
+    /// Give every particle its own mutating copy of the rules of its group
+    #[arg(long, conflicts_with_all = ["search", "evolve", "breed"])]
+    pub genomes: bool,
+
+    /// Let particles surrounded by other groups turn into them
+    #[arg(long, value_enum, value_name = "KIND", conflicts_with_all = ["search", "evolve", "breed"])]
+    pub conversion: Option<ConversionKind>,
+
+    /// Run another universe with the same rules next to the main one, with the given settings
+    /// changed, e.g. drag_coef=0.8,g=0.2. May be given several times
+    #[arg(
+        long = "universe",
+        value_name = "SETTING=VALUE[,...]",
+        value_parser = parse_overrides,
+        conflicts_with_all = ["search", "evolve", "breed"]
+    )]
+    pub universes: Vec<SettingsOverrides>,
+
+    /// Write simulation metrics to this CSV file
+    #[arg(long, value_name = "FILE")]
+    pub metrics: Option<PathBuf>,
+
+    /// Simulation steps between two rows of metrics
+    #[arg(long, value_name = "N", default_value_t = 10, requires = "metrics")]
+    pub metrics_interval: u64,
+
+    /// Window width in logical pixels
+    #[arg(long, default_value_t = 1280.)]
+    pub window_width: f32,
+
+    /// Window height in logical pixels
+    #[arg(long, default_value_t = 720.)]
+    pub window_height: f32,
+
+    /// Start in borderless fullscreen
+    #[arg(long)]
+    pub fullscreen: bool,
+}
+
+impl Cli {
+    // Overrides the given settings with every setting passed on the command line
+    pub fn apply_settings(&self, settings: &mut Settings) {
+        if let Some(g) = self.g {
+            settings.g = g;
+        }
+        if let Some(mass) = self.mass {
+            settings.mass = mass;
+        }
+        if let Some(drag_coef) = self.drag_coef {
+            settings.drag_coef = drag_coef;
+        }
+        if let Some(max_dist) = self.max_dist {
+            settings.max_dist = max_dist;
+        }
+        if let Some(max_velocity) = self.max_velocity {
+            settings.max_velocity = max_velocity;
+        }
+        if let Some(max_force) = self.max_force {
+            settings.max_force = max_force;
+        }
+        if let Some(edge_mode) = self.edge_mode {
+            settings.edge_mode = edge_mode;
+        }
+        if let Some(wall_range) = self.wall_range {
+            settings.wall_range = wall_range;
+        }
+        if let Some(wall_strength) = self.wall_strength {
+            settings.wall_strength = wall_strength;
+        }
+        for source in &self.disable_forces {
+            settings.forces.disable(*source);
+        }
+        if let Some(integrator) = self.integrator {
+            settings.integrator = integrator;
+        }
+        if let Some(temperature) = self.temperature {
+            settings.temperature = temperature;
+        }
+        if !self.group_temperatures.is_empty() {
+            settings.group_temperatures = self.group_temperatures.clone();
+        }
+        if let Some(cooling_rate) = self.cooling_rate {
+            settings.cooling_rate = cooling_rate;
+        }
+        if let Some(min_temperature) = self.min_temperature {
+            settings.min_temperature = min_temperature;
+        }
+        if self.deterministic {
+            settings.deterministic = true;
+        }
+    }
+
+    // Overrides the given world size with the dimensions passed on the command line
+    pub fn apply_world(&self, world: &mut WorldSize) {
+        if let Some(width) = self.world_width {
+            world.width = width;
+        }
+        if let Some(height) = self.world_height {
+            world.height = height;
+        }
+    }
+
+    // Overrides the configured recording settings with the ones passed on the command line
+    pub fn apply_recording(&self, recording: &mut RecordingSettings) {
+        if let Some(interval) = self.record_interval {
+            recording.interval = interval;
+        }
+        if let Some((width, height)) = self.record_size {
+            recording.width = width;
+            recording.height = height;
+        }
+    }
+
+    // Particle amounts for `groups` groups, if any were passed
+    pub fn amounts(&self, groups: usize) -> Result<Option<Vec<usize>>, String> {
+        match self.amount.len() {
+            0 => Ok(None),
+            1 => Ok(Some(vec![self.amount[0]; groups])),
+            len if len == groups => Ok(Some(self.amount.clone())),
+            len => Err(format!(
+                "--amount has {} values but there are {} groups",
+                len, groups
+            )),
+        }
+    }
+}
+
+// Settings a universe changes compared to the main world
+#[derive(Clone, Debug, Default)]
+pub struct SettingsOverrides {
+    pub g: Option<f32>,
+    pub mass: Option<f32>,
+    pub drag_coef: Option<f32>,
+    pub max_dist: Option<f32>,
+    pub max_velocity: Option<f32>,
+    pub edge_mode: Option<EdgeMode>,
+}
+
+impl SettingsOverrides {
+    pub fn apply(&self, settings: &Settings) -> Settings {
+        let mut settings = settings.clone();
+        if let Some(g) = self.g {
+            settings.g = g;
+        }
+        if let Some(mass) = self.mass {
+            settings.mass = mass;
+        }
+        if let Some(drag_coef) = self.drag_coef {
+            settings.drag_coef = drag_coef;
+        }
+        if let Some(max_dist) = self.max_dist {
+            settings.max_dist = max_dist;
+        }
+        if let Some(max_velocity) = self.max_velocity {
+            settings.max_velocity = max_velocity;
+        }
+        if let Some(edge_mode) = self.edge_mode {
+            settings.edge_mode = edge_mode;
+        }
+        settings
+    }
+}
+
+fn parse_overrides(value: &str) -> Result<SettingsOverrides, String> {
+    let mut overrides = SettingsOverrides::default();
+    for pair in value.split(',').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("expected SETTING=VALUE, got {}", pair))?;
+        let number = || {
+            value
+                .parse::<f32>()
+                .map_err(|err| format!("{}: {}", key, err))
+        };
+        match key {
+            "g" => overrides.g = Some(number()?),
+            "mass" => overrides.mass = Some(number()?),
+            "drag_coef" => overrides.drag_coef = Some(number()?),
+            "max_dist" => overrides.max_dist = Some(number()?),
+            "max_velocity" => overrides.max_velocity = Some(number()?),
+            "edge_mode" => overrides.edge_mode = Some(EdgeMode::from_str(value, true)?),
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+    }
+    Ok(overrides)
+}
+
+fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    let (width, height) = value
+        .split_once('x')
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got {}", value))?;
+    let side = |side: &str| match side.parse::<u32>() {
+        Ok(0) => Err("must not be 0".to_string()),
+        Ok(side) => Ok(side),
+        Err(err) => Err(format!("{}", err)),
+    };
+    Ok((side(width)?, side(height)?))
+}
+
+fn parse_universes(value: &str) -> Result<usize, String> {
+    let universes: usize = value.parse().map_err(|err| format!("{}", err))?;
+    if !(4..=9).contains(&universes) {
+        return Err("must be between 4 and 9".to_string());
+    }
+    Ok(universes)
+}
+
+fn parse_groups(value: &str) -> Result<usize, String> {
+    let groups: usize = value.parse().map_err(|err| format!("{}", err))?;
+    if !(1..=MAX_GROUPS).contains(&groups) {
+        return Err(format!("must be between 1 and {}", MAX_GROUPS));
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_change_only_the_given_settings() {
+        let overrides = parse_overrides("drag_coef=0.8,g=0.2,edge_mode=bounce").unwrap();
+        let settings = overrides.apply(&Settings::default());
+        assert_eq!(settings.drag_coef, 0.8);
+        assert_eq!(settings.g, 0.2);
+        assert_eq!(settings.edge_mode, EdgeMode::BOUNCE);
+        assert_eq!(settings.mass, Settings::default().mass);
+        assert_eq!(settings.max_dist, Settings::default().max_dist);
+    }
+
+    #[test]
+    fn empty_overrides_keep_the_settings() {
+        let overrides = parse_overrides("").unwrap();
+        assert!(overrides.apply(&Settings::default()) == Settings::default());
+        assert_eq!(parse_overrides("mass=2,").unwrap().mass, Some(2.));
+    }
+
+    #[test]
+    fn malformed_overrides_are_refused() {
+        assert!(parse_overrides("g").is_err());
+        assert!(parse_overrides("g=fast").is_err());
+        assert!(parse_overrides("gravity=1").is_err());
+        assert!(parse_overrides("edge_mode=sideways").is_err());
+    }
+
+    #[test]
+    fn recording_flags_override_the_configured_recording() {
+        let cli = Cli::try_parse_from([
+            "ParticleLife",
+            "--record-interval",
+            "3",
+            "--record-size",
+            "640x360",
+        ])
+        .unwrap();
+        let mut recording = RecordingSettings::default();
+        cli.apply_recording(&mut recording);
+        assert_eq!(
+            (recording.interval, recording.width, recording.height),
+            (3, 640, 360)
+        );
+        assert_eq!(recording.fps, RecordingSettings::default().fps);
+
+        assert!(parse_size("640").is_err());
+        assert!(parse_size("0x360").is_err());
+        assert!(parse_size("640x-1").is_err());
+    }
+
+    #[test]
+    fn group_temperatures_apply_to_the_settings() {
+        let cli = Cli::try_parse_from(["ParticleLife", "--group-temperatures", "0.5,2"]).unwrap();
+        let mut settings = Settings::default();
+        cli.apply_settings(&mut settings);
+        assert_eq!(settings.group_temperatures, vec![0.5, 2.]);
+        assert_eq!(settings.group_temperature(2), 1.);
+        assert_eq!(cli.groups, None);
+    }
+
+    #[test]
+    fn universes_are_given_one_flag_each() {
+        let cli = Cli::try_parse_from([
+            "ParticleLife",
+            "--universe",
+            "g=0.2",
+            "--universe",
+            "max_velocity=5",
+        ])
+        .unwrap();
+        assert_eq!(cli.universes.len(), 2);
+        assert_eq!(cli.universes[0].g, Some(0.2));
+        assert_eq!(cli.universes[1].max_velocity, Some(5.));
+        assert!(
+            Cli::try_parse_from(["ParticleLife", "--universe", "g=0.2", "--evolve", "3"]).is_err()
+        );
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::HashMap;
+
+use bevy::{
+    prelude::*,
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+    sprite::MaterialMesh2dBundle,
+};
+use iyes_loopless::prelude::IntoConditionalSystem;
+use leafwing_input_manager::prelude::ActionState;
+
+use crate::{
+    camera::{CameraFollow, FollowMode},
+    entity::particle::{GroupId, ParticleMarker, Position, Velocity},
+    metrics::MetricsRecorder,
+    resources::{actions::Action, chunks::Chunks, rules::Rules, tick::Tick},
+};
+
+// Clusters dissolving after living this many ticks are worth a line in the log
+const LONG_LIVED_TICKS: u64 = 600;
+
+// Distance between the outline and the outermost particles of a cluster
+const OUTLINE_MARGIN: f32 = 4.;
+
+#[derive(Resource)]
+pub struct ClusterSettings {
+    // Particles closer than this are part of the same cluster
+    pub link_distance: f32,
+    // Smaller groups of particles are noise rather than clusters
+    pub min_size: usize,
+    // Simulation ticks between two detections, which only run while the overlay, the metrics or
+    // a camera following a cluster use them
+    pub interval: u64,
+    pub overlay: bool,
+}
+
+impl Default for ClusterSettings {
+    fn default() -> Self {
+        Self {
+            link_distance: 20.,
+            min_size: 8,
+            interval: 1,
+            overlay: false,
+        }
+    }
+}
+
+// A group of particles moving together, tracked across detections by the particles it shares
+#[derive(Clone)]
+pub struct Cluster {
+    pub id: u64,
+    pub members: Vec<Entity>,
+    // Number of members of every group, indexed by group id
+    pub composition: Vec<usize>,
+    pub center: Vec2,
+    pub velocity: Vec2,
+    // Tick at which the cluster was first detected
+    pub born: u64,
+}
+
+impl Cluster {
+    pub fn size(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn lifespan(&self, tick: u64) -> u64 {
+        tick.saturating_sub(self.born)
+    }
+
+    pub fn dominant_group(&self) -> usize {
+        (0..self.composition.len())
+            .max_by_key(|group| self.composition[*group])
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct Clusters {
+    pub clusters: Vec<Cluster>,
+    next_id: u64,
+}
+
+impl Clusters {
+    // Replaces the clusters with newly found ones, carrying over the ids of the clusters they
+    // continue, and returns the clusters that dissolved. `particle` looks up the position,
+    // velocity and group of a member
+    pub fn update<const S: usize>(
+        &mut self,
+        found: Vec<Vec<Entity>>,
+        tick: u64,
+        particle: impl Fn(Entity) -> Option<(Vec2, Vec2, usize)>,
+    ) -> Vec<Cluster> {
+        let matches = match_clusters(&self.clusters, &found);
+
+        let mut matched = vec![false; self.clusters.len()];
+        let mut next = Vec::with_capacity(found.len());
+        for (members, previous_index) in found.into_iter().zip(matches) {
+            let (id, born) = match previous_index {
+                Some(index) => {
+                    matched[index] = true;
+                    let previous = &self.clusters[index];
+                    (previous.id, previous.born)
+                }
+                None => {
+                    self.next_id += 1;
+                    (self.next_id, tick)
+                }
+            };
+
+            let mut composition = vec![0; S];
+            let mut position_sum = Vec2::ZERO;
+            let mut velocity_sum = Vec2::ZERO;
+            for (pos, vel, group) in members.iter().filter_map(|entity| particle(*entity)) {
+                composition[group] += 1;
+                position_sum += pos;
+                velocity_sum += vel;
+            }
+
+            let size = members.len() as f32;
+            next.push(Cluster {
+                id,
+                members,
+                composition,
+                center: position_sum / size,
+                velocity: velocity_sum / size,
+                born,
+            });
+        }
+
+        let previous = std::mem::replace(&mut self.clusters, next);
+        previous
+            .into_iter()
+            .zip(matched)
+            .filter(|(_, matched)| !matched)
+            .map(|(cluster, _)| cluster)
+            .collect()
+    }
+}
+
+pub struct ClusterPlugin<const S: usize>;
+
+impl<const S: usize> Plugin for ClusterPlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ClusterSettings>()
+            .init_resource::<Clusters>()
+            .add_system(
+                detect_clusters::<S>
+                    .run_if_resource_exists::<Chunks>()
+                    .label("clusters")
+                    .after("tick"),
+            );
+    }
+}
+
+pub struct ClusterOverlayPlugin<const S: usize>;
+
+impl<const S: usize> Plugin for ClusterOverlayPlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ClusterOutlines>()
+            .add_system(cluster_controls.before("clusters"))
+            .add_system(report_clusters.after("clusters"))
+            .add_system(draw_cluster_outlines::<S>.after("clusters"));
+    }
+}
+
+// Groups of at least `min_size` particles linked by chains of neighbours within `link_distance`,
+// ordered by the position of their first particle in the chunks
+pub fn find_clusters(chunks: &Chunks, link_distance: f32, min_size: usize) -> Vec<Vec<Entity>> {
+    let particles: Vec<(Entity, Vec2)> = chunks
+        .chunks
+        .iter()
+        .flat_map(|chunk| chunk.particles.iter())
+        .map(|(entity, pos, _)| (*entity, pos.0))
+        .collect();
+    let indices: HashMap<Entity, usize> = particles
+        .iter()
+        .enumerate()
+        .map(|(index, (entity, _))| (*entity, index))
+        .collect();
+
+    let mut parents: Vec<usize> = (0..particles.len()).collect();
+    for (index, (_, pos)) in particles.iter().enumerate() {
+        for (other, _, _) in chunks.particles_within(*pos, link_distance) {
+            if let Some(&other_index) = indices.get(other) {
+                union(&mut parents, index, other_index);
+            }
+        }
+    }
+
+    let mut clusters: Vec<Vec<Entity>> = Vec::new();
+    let mut cluster_of_root: HashMap<usize, usize> = HashMap::new();
+    for (index, (entity, _)) in particles.iter().enumerate() {
+        let root = find(&mut parents, index);
+        let cluster = *cluster_of_root.entry(root).or_insert_with(|| {
+            clusters.push(Vec::new());
+            clusters.len() - 1
+        });
+        clusters[cluster].push(*entity);
+    }
+
+    clusters.retain(|cluster| cluster.len() >= min_size);
+    clusters
+}
+
+fn find(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        // Path halving keeps the trees flat
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+    index
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let a = find(parents, a);
+    let b = find(parents, b);
+    if a != b {
+        parents[a.max(b)] = a.min(b);
+    }
+}
+
+// Pairs every new cluster with the previous cluster it shares the most particles with,
+// largest overlaps first, so a cluster keeps its id while it drifts, grows and sheds particles
+fn match_clusters(previous: &[Cluster], found: &[Vec<Entity>]) -> Vec<Option<usize>> {
+    let previous_of: HashMap<Entity, usize> = previous
+        .iter()
+        .enumerate()
+        .flat_map(|(index, cluster)| cluster.members.iter().map(move |entity| (*entity, index)))
+        .collect();
+
+    let mut candidates: Vec<(usize, usize, usize)> = Vec::new();
+    for (index, members) in found.iter().enumerate() {
+        let mut overlaps: HashMap<usize, usize> = HashMap::new();
+        for entity in members {
+            if let Some(&previous_index) = previous_of.get(entity) {
+                *overlaps.entry(previous_index).or_default() += 1;
+            }
+        }
+        candidates.extend(
+            overlaps
+                .into_iter()
+                .map(|(previous_index, overlap)| (overlap, index, previous_index)),
+        );
+    }
+    candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+
+    let mut matches = vec![None; found.len()];
+    let mut taken = vec![false; previous.len()];
+    for (_, index, previous_index) in candidates {
+        if matches[index].is_none() && !taken[previous_index] {
+            matches[index] = Some(previous_index);
+            taken[previous_index] = true;
+        }
+    }
+    matches
+}
+
+fn detect_clusters<const S: usize>(
+    settings: Res<ClusterSettings>,
+    chunks: Res<Chunks>,
+    tick: Res<Tick>,
+    mut clusters: ResMut<Clusters>,
+    (metrics, follow): (Option<Res<MetricsRecorder>>, Option<Res<CameraFollow>>),
+    particle_query: Query<(&Position, &Velocity, &GroupId), With<ParticleMarker>>,
+) {
+    let following =
+        follow.is_some_and(|follow| matches!(follow.target, Some((_, FollowMode::Cluster))));
+    if !settings.overlay && metrics.is_none() && !following {
+        return;
+    }
+    if !tick.0.is_multiple_of(settings.interval.max(1)) {
+        return;
+    }
+
+    let found = find_clusters(&chunks, settings.link_distance, settings.min_size);
+    let dissolved = clusters.update::<S>(found, tick.0, |entity| {
+        particle_query
+            .get(entity)
+            .ok()
+            .map(|(pos, vel, group)| (pos.0, vel.0, group.0))
+    });
+
+    for cluster in dissolved {
+        if cluster.lifespan(tick.0) >= LONG_LIVED_TICKS {
+            info!(
+                "Cluster {} of {} particles {:?} dissolved after {} ticks",
+                cluster.id,
+                cluster.size(),
+                cluster.composition,
+                cluster.lifespan(tick.0)
+            );
+        }
+    }
+}
+
+fn cluster_controls(
+    mut settings: ResMut<ClusterSettings>,
+    action_query: Query<&ActionState<Action>>,
+) {
+    let action_state = action_query.single();
+
+    if action_state.just_pressed(Action::ToggleClusters) {
+        settings.overlay = !settings.overlay;
+    }
+}
+
+// Report the largest clusters whenever the overlay is shown, once they have been detected
+fn report_clusters(tick: Res<Tick>, settings: Res<ClusterSettings>, clusters: Res<Clusters>) {
+    if !settings.is_changed() || !settings.overlay {
+        return;
+    }
+
+    let mut largest: Vec<&Cluster> = clusters.clusters.iter().collect();
+    largest.sort_by_key(|cluster| std::cmp::Reverse(cluster.size()));
+    info!("{} clusters", clusters.clusters.len());
+    for cluster in largest.into_iter().take(10) {
+        info!(
+            "Cluster {}: {} particles {:?}, velocity ({:.2}, {:.2}), {} ticks old",
+            cluster.id,
+            cluster.size(),
+            cluster.composition,
+            cluster.velocity.x,
+            cluster.velocity.y,
+            cluster.lifespan(tick.0)
+        );
+    }
+}
+
+// Outline entity of every cluster on screen, by cluster id
+#[derive(Resource, Default)]
+struct ClusterOutlines(HashMap<u64, (Entity, Handle<Mesh>, Handle<ColorMaterial>)>);
+
+fn draw_cluster_outlines<const S: usize>(
+    mut commands: Commands,
+    settings: Res<ClusterSettings>,
+    (rules, clusters): (Res<Rules<S>>, Res<Clusters>),
+    mut outlines: ResMut<ClusterOutlines>,
+    (mut meshes, mut materials): (ResMut<Assets<Mesh>>, ResMut<Assets<ColorMaterial>>),
+    particle_query: Query<&Position, With<ParticleMarker>>,
+) {
+    if !settings.overlay {
+        for (_, (entity, _, _)) in outlines.0.drain() {
+            commands.entity(entity).despawn();
+        }
+        return;
+    }
+    if !clusters.is_changed() && !settings.is_changed() {
+        return;
+    }
+
+    let mut stale: Vec<u64> = outlines.0.keys().copied().collect();
+    for cluster in &clusters.clusters {
+        let points: Vec<Vec2> = particle_query
+            .iter_many(&cluster.members)
+            .map(|pos| pos.0)
+            .collect();
+        let hull = convex_hull(points);
+        if hull.len() < 2 {
+            continue;
+        }
+        let mesh = outline_mesh(&hull, cluster.center);
+        let color = rules.colors[cluster.dominant_group()];
+
+        stale.retain(|id| *id != cluster.id);
+        if let Some((_, mesh_handle, material_handle)) = outlines.0.get(&cluster.id) {
+            if let Some(existing) = meshes.get_mut(mesh_handle) {
+                *existing = mesh;
+            }
+            if let Some(material) = materials.get_mut(material_handle) {
+                material.color = color;
+            }
+            continue;
+        }
+
+        let mesh_handle = meshes.add(mesh);
+        let material_handle = materials.add(ColorMaterial::from(color));
+        let entity = commands
+            .spawn(MaterialMesh2dBundle {
+                mesh: mesh_handle.clone().into(),
+                material: material_handle.clone(),
+                // Above the particles
+                transform: Transform::from_xyz(0., 0., 1.),
+                ..default()
+            })
+            .id();
+        outlines
+            .0
+            .insert(cluster.id, (entity, mesh_handle, material_handle));
+    }
+
+    for id in stale {
+        if let Some((entity, _, _)) = outlines.0.remove(&id) {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+// Closed line loop around the hull, pushed outwards from the center by `OUTLINE_MARGIN`
+fn outline_mesh(hull: &[Vec2], center: Vec2) -> Mesh {
+    let positions: Vec<[f32; 3]> = hull
+        .iter()
+        .map(|point| {
+            let outwards = (*point - center).normalize_or_zero();
+            let point = *point + outwards * OUTLINE_MARGIN;
+            [point.x, point.y, 0.]
+        })
+        .collect();
+    let count = positions.len() as u32;
+
+    let mut mesh = Mesh::new(PrimitiveTopology::LineList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_indices(Some(Indices::U32(
+        (0..count).flat_map(|i| [i, (i + 1) % count]).collect(),
+    )));
+    mesh
+}
+
+// Monotone chain, returns the hull counter clockwise without repeating the first point
+fn convex_hull(mut points: Vec<Vec2>) -> Vec<Vec2> {
+    points.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    points.dedup();
+    if points.len() < 3 {
+        return points;
+    }
+
+    let cross = |o: Vec2, a: Vec2, b: Vec2| (a - o).perp_dot(b - o);
+    let mut hull: Vec<Vec2> = Vec::with_capacity(points.len() * 2);
+    for pass in [points.clone(), points.into_iter().rev().collect()] {
+        let start = hull.len();
+        for point in pass {
+            while hull.len() >= start + 2
+                && cross(hull[hull.len() - 2], hull[hull.len() - 1], point) <= 0.
+            {
+                hull.pop();
+            }
+            hull.push(point);
+        }
+        // The last point of each half is the first point of the other
+        hull.pop();
+    }
+    hull
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Chunks of 10 covering -20..20 on both axes, holding a particle at each position
+    fn chunks(positions: &[(f32, f32)]) -> Chunks {
+        let mut chunks = Chunks::new(4, 4, 10, Vec2::splat(-20.));
+        for (index, (x, y)) in positions.iter().enumerate() {
+            chunks.insert_particle(
+                Entity::from_raw(index as u32),
+                Position(Vec2::new(*x, *y)),
+                GroupId(0),
+            );
+        }
+        chunks
+    }
+
+    fn sorted(mut clusters: Vec<Vec<Entity>>) -> Vec<Vec<u32>> {
+        let mut clusters: Vec<Vec<u32>> = clusters
+            .iter_mut()
+            .map(|cluster| {
+                let mut cluster: Vec<u32> = cluster.iter().map(|entity| entity.index()).collect();
+                cluster.sort();
+                cluster
+            })
+            .collect();
+        clusters.sort();
+        clusters
+    }
+
+    #[test]
+    fn chains_across_chunks_are_one_cluster() {
+        // A chain from the lower left to the upper right chunk, and a pair far from it
+        let chunks = chunks(&[
+            (-15., -15.),
+            (-11., -12.),
+            (-7., -9.),
+            (-3., -6.),
+            (1., -3.),
+            (5., 0.),
+            (15., -15.),
+            (16., -13.),
+        ]);
+        assert_eq!(
+            sorted(find_clusters(&chunks, 5., 2)),
+            vec![vec![0, 1, 2, 3, 4, 5], vec![6, 7]]
+        );
+    }
+
+    #[test]
+    fn small_clusters_are_left_out() {
+        let chunks = chunks(&[(-15., -15.), (-13., -15.), (0., 0.), (15., 15.)]);
+        assert_eq!(sorted(find_clusters(&chunks, 5., 2)), vec![vec![0, 1]]);
+        assert_eq!(sorted(find_clusters(&chunks, 5., 1)).len(), 3);
+        assert!(find_clusters(&chunks, 5., 3).is_empty());
+    }
+
+    #[test]
+    fn particles_just_out_of_reach_stay_apart() {
+        let chunks = chunks(&[(0., 0.), (5., 0.), (10.5, 0.)]);
+        assert_eq!(
+            sorted(find_clusters(&chunks, 5., 1)),
+            vec![vec![0, 1], vec![2]]
+        );
+    }
+}
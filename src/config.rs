@@ -0,0 +1,252 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender};
+use iyes_loopless::prelude::IntoConditionalSystem;
+use leafwing_input_manager::prelude::InputMap;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
+
+use crate::{
+    camera::CameraSettings,
+    capture::ScreenshotSettings,
+    conversion::ConversionSettings,
+    ecology::EcologySettings,
+    fields::ForceField,
+    genome::GenomeSettings,
+    interventions::Intervention,
+    minimap::MinimapSettings,
+    recording::RecordingSettings,
+    replay::ReplayPlayer,
+    resources::{
+        actions::{load_input_map, Action, InputConfig},
+        settings::Settings,
+        world::WorldSize,
+    },
+};
+
+pub const CONFIG_PATH: &str = "particlelife.toml";
+
+const DEFAULT_BACKGROUND: &str = "2596be";
+
+// Every section is optional, a missing section leaves the running values alone
+#[derive(Deserialize, Default, Clone)]
+pub struct ConfigFile {
+    pub settings: Option<Settings>,
+    pub world: Option<WorldSize>,
+    pub camera: Option<CameraSettings>,
+    pub minimap: Option<MinimapSettings>,
+    pub rendering: Option<RenderingConfig>,
+    pub screenshots: Option<ScreenshotSettings>,
+    pub recording: Option<RecordingSettings>,
+    pub input: Option<InputConfig>,
+    pub ecology: Option<EcologySettings>,
+    pub conversion: Option<ConversionSettings>,
+    pub genome: Option<GenomeSettings>,
+    pub fields: Option<Vec<ForceField>>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RenderingConfig {
+    // Hex color behind the particles
+    pub background: String,
+}
+
+impl Default for RenderingConfig {
+    fn default() -> Self {
+        Self {
+            background: DEFAULT_BACKGROUND.to_string(),
+        }
+    }
+}
+
+impl RenderingConfig {
+    pub fn background(&self) -> Color {
+        Color::hex(&self.background).unwrap_or_else(|_| {
+            warn!("Ignoring invalid background color {}", self.background);
+            Color::hex(DEFAULT_BACKGROUND).unwrap()
+        })
+    }
+}
+
+impl ConfigFile {
+    // A missing file is an empty configuration
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|err| err.to_string()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+// Sent with the new contents whenever the configuration file was edited
+pub struct ConfigReloaded(pub ConfigFile);
+
+#[derive(Resource)]
+pub struct ConfigWatcher {
+    path: PathBuf,
+    changes: Receiver<()>,
+    // Stops watching when dropped
+    _watcher: RecommendedWatcher,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> notify::Result<Self> {
+        let (sender, changes): (Sender<()>, Receiver<()>) = crossbeam_channel::unbounded();
+        let file_name = path.file_name().map(|name| name.to_os_string());
+
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+                if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    return;
+                }
+                if event
+                    .paths
+                    .iter()
+                    .any(|changed| changed.file_name() == file_name.as_deref())
+                {
+                    let _ = sender.send(());
+                }
+            })?;
+
+        // Editors often replace the file instead of writing to it, so watch its directory
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            changes,
+            _watcher: watcher,
+        })
+    }
+}
+
+pub struct ConfigPlugin {
+    pub path: PathBuf,
+}
+
+impl Plugin for ConfigPlugin {
+    fn build(&self, app: &mut App) {
+        match ConfigWatcher::new(&self.path) {
+            Ok(watcher) => {
+                app.insert_resource(watcher);
+            }
+            Err(err) => warn!("Not watching {} for changes: {}", self.path.display(), err),
+        }
+
+        app.add_event::<ConfigReloaded>()
+            .add_system(
+                reload_config
+                    .run_if_resource_exists::<ConfigWatcher>()
+                    .label("config"),
+            )
+            .add_system(
+                apply_simulation_config
+                    .after("config")
+                    .before("interventions"),
+            )
+            .add_system(apply_view_config.after("config"));
+    }
+}
+
+fn reload_config(watcher: Res<ConfigWatcher>, mut reloads: EventWriter<ConfigReloaded>) {
+    // A single save can produce several events, read the file once for all of them
+    if watcher.changes.try_iter().count() == 0 {
+        return;
+    }
+
+    match ConfigFile::load(&watcher.path) {
+        Ok(config) => {
+            info!("Reloaded {}", watcher.path.display());
+            reloads.send(ConfigReloaded(config));
+        }
+        Err(err) => warn!("Ignoring changes to {}: {}", watcher.path.display(), err),
+    }
+}
+
+fn apply_simulation_config(
+    settings: Res<Settings>,
+    player: Res<ReplayPlayer>,
+    mut world: ResMut<WorldSize>,
+    mut reloads: EventReader<ConfigReloaded>,
+    mut interventions: EventWriter<Intervention>,
+) {
+    for ConfigReloaded(config) in reloads.iter() {
+        if let Some(new_settings) = &config.settings {
+            if *new_settings != *settings {
+                if player.is_playing() {
+                    warn!("Ignoring configured settings while a replay is playing");
+                } else {
+                    // Through an intervention, so replays being recorded pick the change up
+                    interventions.send(Intervention::SetSettings(new_settings.clone()));
+                }
+            }
+        }
+
+        // A new world size respawns all particles in `configure`
+        if let Some(new_world) = config.world {
+            if new_world != *world {
+                *world = new_world;
+            }
+        }
+    }
+}
+
+fn apply_view_config(
+    mut input_config: ResMut<InputConfig>,
+    mut reloads: EventReader<ConfigReloaded>,
+    camera_settings: Option<ResMut<CameraSettings>>,
+    minimap_settings: Option<ResMut<MinimapSettings>>,
+    clear_color: Option<ResMut<ClearColor>>,
+    // Recordings already running keep the settings they started with
+    capture_settings: (
+        Option<ResMut<ScreenshotSettings>>,
+        Option<ResMut<RecordingSettings>>,
+    ),
+    mut input_query: Query<&mut InputMap<Action>>,
+) {
+    let Some(ConfigReloaded(config)) = reloads.iter().last() else {
+        return;
+    };
+
+    if let (Some(new_settings), Some(mut camera_settings)) = (&config.camera, camera_settings) {
+        *camera_settings = new_settings.clone();
+    }
+    // The minimap keeps the width it was created with
+    if let (Some(new_settings), Some(mut minimap_settings)) = (&config.minimap, minimap_settings) {
+        *minimap_settings = MinimapSettings {
+            width: minimap_settings.width,
+            ..new_settings.clone()
+        };
+    }
+    if let (Some(rendering), Some(mut clear_color)) = (&config.rendering, clear_color) {
+        clear_color.0 = rendering.background();
+    }
+    if let (Some(new_settings), Some(mut screenshot_settings)) =
+        (&config.screenshots, capture_settings.0)
+    {
+        *screenshot_settings = new_settings.clone();
+    }
+    if let (Some(new_settings), Some(mut recording_settings)) =
+        (&config.recording, capture_settings.1)
+    {
+        *recording_settings = new_settings.clone();
+    }
+    if let Some(new_input) = &config.input {
+        *input_config = new_input.clone();
+        for mut input_map in input_query.iter_mut() {
+            *input_map = load_input_map(&input_config.bindings);
+        }
+    }
+}
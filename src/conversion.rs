@@ -0,0 +1,193 @@
+use bevy::prelude::*;
+use clap::ValueEnum;
+use iyes_loopless::prelude::IntoConditionalSystem;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+
+use crate::{
+    config::ConfigReloaded,
+    entity::particle::{GroupId, ParticleMarker},
+    genome::Genome,
+    resources::{chunks::Chunks, rules::Rules},
+    simulation::GroupAssets,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum ConversionKind {
+    // Every group converts to the next one when surrounded by it, the last one to the first
+    RockPaperScissors,
+    // Random conversions following from the rules seed
+    Random,
+}
+
+#[derive(Resource, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ConversionSettings {
+    // Particles within this distance count towards converting a particle
+    pub radius: f32,
+    // Particles of the converting group needed around a particle before it may convert
+    pub min_neighbours: usize,
+    // Chance per tick of a surrounded particle to convert
+    pub chance: f32,
+    // Chance of a random conversion to exist between two groups
+    pub density: f32,
+}
+
+impl Default for ConversionSettings {
+    fn default() -> Self {
+        Self {
+            radius: 15.,
+            min_neighbours: 3,
+            chance: 0.05,
+            density: 0.3,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Conversion {
+    // Group the particle turns into
+    pub into: usize,
+    pub chance: f32,
+}
+
+// What a particle of group i turns into when surrounded by particles of group j
+#[derive(Resource)]
+pub struct Conversions<const S: usize> {
+    pub kind: ConversionKind,
+    pub matrix: [[Option<Conversion>; S]; S],
+    rng: StdRng,
+}
+
+impl<const S: usize> Conversions<S> {
+    pub fn new(kind: ConversionKind, seed: u64, settings: &ConversionSettings) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut matrix = [[None; S]; S];
+
+        match kind {
+            ConversionKind::RockPaperScissors => {
+                if S > 1 {
+                    for (group, row) in matrix.iter_mut().enumerate() {
+                        let next = (group + 1) % S;
+                        row[next] = Some(Conversion {
+                            into: next,
+                            chance: settings.chance,
+                        });
+                    }
+                }
+            }
+            ConversionKind::Random => {
+                for (group, row) in matrix.iter_mut().enumerate() {
+                    for (other, conversion) in row.iter_mut().enumerate() {
+                        if group == other || rng.gen::<f32>() >= settings.density {
+                            continue;
+                        }
+                        // Mostly into the surrounding group, sometimes into a third one
+                        let into = if rng.gen_bool(0.8) {
+                            other
+                        } else {
+                            rng.gen_range(0..S)
+                        };
+                        if into != group {
+                            *conversion = Some(Conversion {
+                                into,
+                                chance: rng.gen_range(0.0..=settings.chance),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        Self { kind, matrix, rng }
+    }
+}
+
+// Particles surrounded by particles of certain groups turn into another group
+pub struct ConversionPlugin<const S: usize>;
+
+impl<const S: usize> Plugin for ConversionPlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.add_system(apply_conversion_config.after("config"))
+            .add_system(update_conversions::<S>.before("conversion"))
+            .add_system(
+                convert::<S>
+                    .run_if_resource_exists::<Chunks>()
+                    .label("conversion")
+                    .after("chunks")
+                    .before("tick"),
+            );
+    }
+}
+
+fn apply_conversion_config(
+    mut settings: ResMut<ConversionSettings>,
+    mut reloads: EventReader<ConfigReloaded>,
+) {
+    if let Some(ConfigReloaded(config)) = reloads.iter().last() {
+        if let Some(new_settings) = &config.conversion {
+            if *new_settings != *settings {
+                *settings = new_settings.clone();
+            }
+        }
+    }
+}
+
+fn update_conversions<const S: usize>(
+    rules: Res<Rules<S>>,
+    settings: Res<ConversionSettings>,
+    mut conversions: ResMut<Conversions<S>>,
+) {
+    if rules.is_changed() || settings.is_changed() {
+        *conversions = Conversions::new(conversions.kind, rules.seed, &settings);
+    }
+}
+
+fn convert<const S: usize>(
+    mut commands: Commands,
+    mut chunks: ResMut<Chunks>,
+    settings: Res<ConversionSettings>,
+    group_assets: Res<GroupAssets>,
+    mut conversions: ResMut<Conversions<S>>,
+    mut particle_query: Query<(&mut GroupId, &mut Handle<ColorMaterial>), With<ParticleMarker>>,
+) {
+    let conversions = &mut *conversions;
+
+    // Decide on every particle before changing any, so the order they are visited in doesn't
+    // matter
+    let mut converted = Vec::new();
+    for chunk in &chunks.chunks {
+        for (entity, pos, id) in &chunk.particles {
+            let mut counts = [0usize; S];
+            for (other, _, other_id) in chunks.particles_within(pos.0, settings.radius) {
+                if other != entity {
+                    counts[other_id.0] += 1;
+                }
+            }
+
+            for (other_group, count) in counts.iter().enumerate() {
+                let Some(conversion) = conversions.matrix[id.0][other_group] else {
+                    continue;
+                };
+                if *count >= settings.min_neighbours
+                    && conversions.rng.gen::<f32>() < conversion.chance
+                {
+                    converted.push((*entity, *pos, GroupId(conversion.into)));
+                    break;
+                }
+            }
+        }
+    }
+
+    for (entity, pos, id) in converted {
+        let Ok((mut group_id, mut material)) = particle_query.get_mut(entity) else {
+            continue;
+        };
+        *group_id = id;
+        *material = group_assets.0[id.0].1.clone();
+        chunks.set_group(entity, pos, id);
+
+        // A converted particle takes on the genome of its new group
+        commands.entity(entity).remove::<Genome<S>>();
+    }
+}
@@ -0,0 +1,241 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use iyes_loopless::prelude::IntoConditionalSystem;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+
+use crate::{
+    config::ConfigReloaded,
+    entity::particle::{Energy, GroupId, ParticleMarker, Position, Velocity},
+    genome::{Genome, InheritedGenome},
+    resources::{chunks::Chunks, rules::Rules, settings::Settings},
+    simulation::{spawn_particle, GroupAssets},
+};
+
+// Distance between the two halves of a dividing particle
+const DIVISION_SPACING: f32 = 2.;
+
+#[derive(Resource, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct EcologySettings {
+    // Energy every particle starts with, and the energy of spawned particles and of restored
+    // ones saved without any
+    pub initial_energy: f32,
+    // Energy every particle loses each tick
+    pub decay: f32,
+    // Particles closer than this feed on each other
+    pub feeding_range: f32,
+    // Particles with more energy divide into two with half the energy each
+    pub division_energy: f32,
+    // No particle divides while there are this many
+    pub max_particles: usize,
+    // Chance of a group to feed on another, and the most energy it takes per tick from a
+    // single particle of that group
+    pub food_chance: f32,
+    pub max_feeding_rate: f32,
+    // Energy a particle of the row group takes per tick from one of the column group, replacing
+    // the random food web when it has a row and a column for every group
+    pub predation: Option<Vec<Vec<f32>>>,
+}
+
+impl Default for EcologySettings {
+    fn default() -> Self {
+        Self {
+            initial_energy: 1.0,
+            decay: 0.002,
+            feeding_range: 20.,
+            division_energy: 2.0,
+            max_particles: 10000,
+            food_chance: 0.3,
+            max_feeding_rate: 0.005,
+            predation: None,
+        }
+    }
+}
+
+// Energy a particle of group i takes per tick from every particle of group j within feeding range
+#[derive(Resource)]
+pub struct Predation<const S: usize>(pub [[f32; S]; S]);
+
+impl<const S: usize> Predation<S> {
+    // The configured food web, or a random one following from the rules seed without one
+    pub fn new(seed: u64, ecology: &EcologySettings) -> Self {
+        if let Some(matrix) = &ecology.predation {
+            match Self::from_matrix(matrix) {
+                Ok(predation) => return predation,
+                Err(err) => warn!("Ignoring the configured predation matrix: {}", err),
+            }
+        }
+        Self::from_seed(seed, ecology)
+    }
+
+    pub fn from_matrix(matrix: &[Vec<f32>]) -> Result<Self, String> {
+        if matrix.len() != S || matrix.iter().any(|row| row.len() != S) {
+            return Err(format!("expected {} rows of {} rates", S, S));
+        }
+
+        let mut predation = [[0.; S]; S];
+        for (row, rates) in predation.iter_mut().zip(matrix) {
+            row.copy_from_slice(rates);
+        }
+        Ok(Self(predation))
+    }
+
+    // Every run with the same rules has the same food web
+    pub fn from_seed(seed: u64, ecology: &EcologySettings) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut predation = [[0.; S]; S];
+        for (i, row) in predation.iter_mut().enumerate() {
+            for (j, rate) in row.iter_mut().enumerate() {
+                if i != j && rng.gen::<f32>() < ecology.food_chance {
+                    *rate = rng.gen_range(0.0..=ecology.max_feeding_rate);
+                }
+            }
+        }
+        Self(predation)
+    }
+}
+
+impl<const S: usize> fmt::Display for Predation<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in self.0.iter() {
+            let rates: Vec<String> = row.iter().map(|rate| format!("{:.4}", rate)).collect();
+            writeln!(f, "[{}]", rates.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// Particles gain energy from the groups they feed on and lose it to the groups feeding on them,
+// die when it runs out and divide when they have plenty
+pub struct EcologyPlugin<const S: usize>;
+
+impl<const S: usize> Plugin for EcologyPlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Predation::<S>([[0.; S]; S]))
+            .add_system(apply_ecology_config.after("config"))
+            .add_system(update_predation::<S>.before("energy"))
+            .add_system(
+                feed::<S>
+                    .run_if_resource_exists::<Chunks>()
+                    .label("energy")
+                    .after("chunks"),
+            )
+            .add_system(
+                live_and_die::<S>
+                    .run_if_resource_exists::<GroupAssets>()
+                    .after("energy")
+                    .before("tick"),
+            );
+    }
+}
+
+fn apply_ecology_config(
+    mut ecology: ResMut<EcologySettings>,
+    mut reloads: EventReader<ConfigReloaded>,
+) {
+    if let Some(ConfigReloaded(config)) = reloads.iter().last() {
+        if let Some(new_ecology) = &config.ecology {
+            if *new_ecology != *ecology {
+                *ecology = new_ecology.clone();
+            }
+        }
+    }
+}
+
+fn update_predation<const S: usize>(
+    rules: Res<Rules<S>>,
+    ecology: Res<EcologySettings>,
+    mut predation: ResMut<Predation<S>>,
+) {
+    if rules.is_changed() || ecology.is_changed() {
+        *predation = Predation::new(rules.seed, &ecology);
+        info!(
+            "Predation, the row group feeding on the column group:\n{}",
+            *predation
+        );
+    }
+}
+
+fn feed<const S: usize>(
+    mut commands: Commands,
+    chunks: Res<Chunks>,
+    ecology: Res<EcologySettings>,
+    predation: Res<Predation<S>>,
+    mut particle_query: Query<
+        (Entity, Option<&mut Energy>, &Position, &GroupId),
+        With<ParticleMarker>,
+    >,
+) {
+    for (entity, energy, pos, id) in particle_query.iter_mut() {
+        // New particles start out with the initial energy, restored ones keep what they had
+        let Some(mut energy) = energy else {
+            commands
+                .entity(entity)
+                .insert(Energy(ecology.initial_energy));
+            continue;
+        };
+
+        let mut change = -ecology.decay;
+        for (other, _, other_id) in chunks.particles_within(pos.0, ecology.feeding_range) {
+            if *other != entity {
+                change += predation.0[id.0][other_id.0] - predation.0[other_id.0][id.0];
+            }
+        }
+        energy.0 += change;
+    }
+}
+
+type LivingParticleQuery<'w, 's, const S: usize> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static mut Energy,
+        &'static Position,
+        &'static Velocity,
+        &'static GroupId,
+        Option<&'static Genome<S>>,
+    ),
+    With<ParticleMarker>,
+>;
+
+fn live_and_die<const S: usize>(
+    mut commands: Commands,
+    settings: Res<Settings>,
+    ecology: Res<EcologySettings>,
+    group_assets: Res<GroupAssets>,
+    mut particle_query: LivingParticleQuery<S>,
+) {
+    let mut population = particle_query.iter().count();
+
+    for (entity, mut energy, pos, vel, id, genome) in particle_query.iter_mut() {
+        if energy.0 <= 0. {
+            commands.entity(entity).despawn();
+            population -= 1;
+        } else if energy.0 > ecology.division_energy && population < ecology.max_particles {
+            energy.0 /= 2.;
+
+            // Placed across the direction of motion, so both halves keep the same velocity
+            let across = vel.0.perp().try_normalize().unwrap_or(Vec2::X);
+            let (mesh, material) = &group_assets.0[id.0];
+            let child = spawn_particle(
+                &mut commands,
+                &settings,
+                mesh,
+                material,
+                pos.0 + across * DIVISION_SPACING,
+                vel.0,
+                id.0,
+            );
+            commands.entity(child).insert(Energy(energy.0));
+            if let Some(genome) = genome {
+                commands
+                    .entity(child)
+                    .insert(InheritedGenome(genome.clone()));
+            }
+            population += 1;
+        }
+    }
+}
@@ -14,13 +14,31 @@ pub struct Particle {
     pub velocity: Velocity,
     pub position: Position,
     pub group_id: GroupId,
+    pub force: Force,
+    pub previous_force: PreviousForce,
 }
 
 #[derive(Component, Copy, Clone)]
 pub struct GroupId(pub usize);
 
+// Stays the same across snapshots and replays, unlike the entity of a particle
+#[derive(Component, Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ParticleIndex(pub u64);
+
 #[derive(Component, Copy, Clone)]
 pub struct Velocity(pub Vec2);
 
 #[derive(Component, Copy, Clone)]
 pub struct Position(pub Vec2);
+
+// Sum of all forces acting on a particle this tick, turned into motion by the integrator
+#[derive(Component, Copy, Clone, Default)]
+pub struct Force(pub Vec2);
+
+// Force of the tick before, none before the first tick of a particle
+#[derive(Component, Copy, Clone, Default)]
+pub struct PreviousForce(pub Option<Vec2>);
+
+// Only carried in ecology mode, a particle dies when it runs out
+#[derive(Component, Copy, Clone)]
+pub struct Energy(pub f32);
@@ -0,0 +1,279 @@
+use std::fmt;
+
+use bevy::prelude::{Entity, Vec2};
+
+use crate::{
+    clusters::{find_clusters, ClusterSettings, Clusters},
+    resources::{rules::Rules, settings::Settings, world::WorldSize},
+    universe::Universe,
+};
+
+// Cells along each axis of the grid the spatial entropy is measured on
+const ENTROPY_GRID: usize = 16;
+
+// Below this entropy the particles start collapsing into a few points
+const LOW_ENTROPY: f32 = 0.2;
+
+// Mean speed below which a simulation counts as frozen
+const MIN_MEAN_SPEED: f32 = 0.05;
+
+pub struct EvaluationSettings {
+    pub ticks: u64,
+    // Ticks to let the particles settle before anything is measured
+    pub warmup: u64,
+    // Ticks between two measurements
+    pub sample_interval: u64,
+    // Clusters have to survive this many ticks to count as stable
+    pub stable_ticks: u64,
+    // Distance per tick a stable cluster has to move at to count as moving
+    pub min_cluster_speed: f32,
+}
+
+impl Default for EvaluationSettings {
+    fn default() -> Self {
+        Self {
+            ticks: 1000,
+            warmup: 200,
+            sample_interval: 10,
+            stable_ticks: 300,
+            min_cluster_speed: 0.1,
+        }
+    }
+}
+
+// Mean speed of the particles, each given by its entity, position, velocity and group
+pub fn mean_speed(particles: &[(Entity, Vec2, Vec2, usize)]) -> f32 {
+    let total: f32 = particles.iter().map(|(_, _, vel, _)| vel.length()).sum();
+    total / particles.len().max(1) as f32
+}
+
+// Shannon entropy of the particle density on a coarse grid, 0 when all particles share a
+// cell and 1 when they are spread evenly
+pub fn spatial_entropy(particles: &[(Entity, Vec2, Vec2, usize)], world: &WorldSize) -> f32 {
+    let mut cells = vec![0usize; ENTROPY_GRID * ENTROPY_GRID];
+    let extent = world.extent();
+    for (_, pos, _, _) in particles {
+        let cell = ((*pos + world.bounds()) / extent * ENTROPY_GRID as f32)
+            .clamp(Vec2::ZERO, Vec2::splat(ENTROPY_GRID as f32 - 1.));
+        cells[cell.x as usize + cell.y as usize * ENTROPY_GRID] += 1;
+    }
+
+    let total = particles.len().max(1) as f32;
+    let entropy: f32 = cells
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f32 / total;
+            -p * p.ln()
+        })
+        .sum();
+    entropy / (cells.len() as f32).ln()
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Score {
+    pub total: f32,
+    // Clusters alive at the end that survived long enough and kept moving
+    pub moving_clusters: usize,
+    pub clusters: usize,
+    // Averaged over all measurements
+    pub mean_speed: f32,
+    pub entropy: f32,
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "score {:.3}: {} moving of {} clusters, mean speed {:.3}, entropy {:.3}",
+            self.total, self.moving_clusters, self.clusters, self.mean_speed, self.entropy
+        )
+    }
+}
+
+impl Score {
+    // Rewards stable moving clusters in a simulation that neither freezes nor spreads into a
+    // uniform gas nor collapses into a few points
+    fn new(moving_clusters: usize, clusters: usize, mean_speed: f32, entropy: f32) -> Self {
+        let motion = (mean_speed / MIN_MEAN_SPEED).min(1.);
+        let structure = (1. - entropy) * (entropy / LOW_ENTROPY).min(1.);
+
+        Self {
+            total: (1 + moving_clusters) as f32 * motion * structure,
+            moving_clusters,
+            clusters,
+            mean_speed,
+            entropy,
+        }
+    }
+}
+
+// Where a rule set ended up after an evaluation, for measures beyond the score
+pub struct Evaluation<const S: usize> {
+    pub score: Score,
+    pub universe: Universe<S>,
+    pub clusters: Clusters,
+}
+
+// Runs the rules for `evaluation.ticks` ticks and scores how interesting the result is
+pub fn evaluate<const S: usize>(
+    rules: Rules<S>,
+    settings: Settings,
+    world: WorldSize,
+    evaluation: &EvaluationSettings,
+) -> Evaluation<S> {
+    let cluster_settings = ClusterSettings::default();
+    // The same rules have to score the same every time
+    let settings = Settings {
+        deterministic: true,
+        ..settings
+    };
+    let mut universe = Universe::new(rules, settings, world);
+    let mut clusters = Clusters::default();
+
+    let mut samples = 0;
+    let mut speed_sum = 0.;
+    let mut entropy_sum = 0.;
+    while universe.tick() < evaluation.ticks {
+        universe.step();
+        let tick = universe.tick();
+        if tick < evaluation.warmup || !tick.is_multiple_of(evaluation.sample_interval.max(1)) {
+            continue;
+        }
+        let Some(chunks) = universe.chunks() else {
+            continue;
+        };
+
+        let found = find_clusters(
+            chunks,
+            cluster_settings.link_distance,
+            cluster_settings.min_size,
+        );
+        clusters.update::<S>(found, tick, |entity| universe.particle(entity));
+
+        let particles = universe.particles();
+        samples += 1;
+        speed_sum += mean_speed(&particles);
+        entropy_sum += spatial_entropy(&particles, &world);
+    }
+
+    let moving_clusters = clusters
+        .clusters
+        .iter()
+        .filter(|cluster| {
+            cluster.lifespan(universe.tick()) >= evaluation.stable_ticks
+                && cluster.velocity.length() >= evaluation.min_cluster_speed
+        })
+        .count();
+    let samples = samples.max(1) as f32;
+
+    let score = Score::new(
+        moving_clusters,
+        clusters.clusters.len(),
+        speed_sum / samples,
+        entropy_sum / samples,
+    );
+
+    Evaluation {
+        score,
+        universe,
+        clusters,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORLD: WorldSize = WorldSize {
+        width: 160,
+        height: 160,
+    };
+
+    fn particles(positions: &[(f32, f32)], vel: Vec2) -> Vec<(Entity, Vec2, Vec2, usize)> {
+        positions
+            .iter()
+            .enumerate()
+            .map(|(index, (x, y))| (Entity::from_raw(index as u32), Vec2::new(*x, *y), vel, 0))
+            .collect()
+    }
+
+    // One particle in the middle of every cell of the entropy grid
+    fn spread() -> Vec<(f32, f32)> {
+        let cell = WORLD.extent() / ENTROPY_GRID as f32;
+        (0..ENTROPY_GRID * ENTROPY_GRID)
+            .map(|index| {
+                let pos = Vec2::new(
+                    (index % ENTROPY_GRID) as f32 + 0.5,
+                    (index / ENTROPY_GRID) as f32 + 0.5,
+                ) * cell
+                    - WORLD.bounds();
+                (pos.x, pos.y)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn entropy_grows_as_the_particles_spread() {
+        assert_eq!(spatial_entropy(&[], &WORLD), 0.);
+        assert_eq!(
+            spatial_entropy(&particles(&[(3., 4.); 50], Vec2::ZERO), &WORLD),
+            0.
+        );
+
+        let halves = [(-100., 0.), (100., 0.)].repeat(25);
+        let halves = spatial_entropy(&particles(&halves, Vec2::ZERO), &WORLD);
+        assert!((halves - 2f32.ln() / 256f32.ln()).abs() < 1e-6);
+
+        let spread = spatial_entropy(&particles(&spread(), Vec2::ZERO), &WORLD);
+        assert!((spread - 1.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn particles_outside_the_world_count_in_the_edge_cells() {
+        let outside = particles(&[(-1000., -1000.), (1000., 1000.)], Vec2::ZERO);
+        let edges = particles(&[(-160., -160.), (159., 159.)], Vec2::ZERO);
+        assert_eq!(
+            spatial_entropy(&outside, &WORLD),
+            spatial_entropy(&edges, &WORLD)
+        );
+    }
+
+    #[test]
+    fn mean_speed_averages_the_velocities() {
+        assert_eq!(mean_speed(&[]), 0.);
+        let mut moving = particles(&[(0., 0.); 2], Vec2::new(3., 4.));
+        moving[1].2 = Vec2::ZERO;
+        assert_eq!(mean_speed(&moving), 2.5);
+    }
+
+    #[test]
+    fn frozen_uniform_and_collapsed_simulations_score_nothing() {
+        assert_eq!(Score::new(3, 3, 0., 0.5).total, 0.);
+        assert_eq!(Score::new(3, 3, 1., 1.).total, 0.);
+        assert_eq!(Score::new(3, 3, 1., 0.).total, 0.);
+
+        let structured = Score::new(0, 3, 1., 0.5);
+        assert!(structured.total > 0.);
+        assert!(Score::new(2, 3, 1., 0.5).total > structured.total);
+    }
+
+    #[test]
+    fn the_same_rules_score_the_same() {
+        let mut rules = Rules::<2>::from_seed(5);
+        rules.amount = [40; 2];
+        let evaluation = EvaluationSettings {
+            ticks: 40,
+            warmup: 10,
+            sample_interval: 5,
+            ..Default::default()
+        };
+        let score = || evaluate(rules.clone(), Settings::default(), WORLD, &evaluation).score;
+
+        let (a, b) = (score(), score());
+        assert_eq!(a.total, b.total);
+        assert_eq!(a.mean_speed, b.mean_speed);
+        assert_eq!(a.entropy, b.entropy);
+        assert!(a.mean_speed > 0. && a.entropy > 0.);
+    }
+}
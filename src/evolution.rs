@@ -0,0 +1,457 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use bevy::prelude::Vec2;
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+
+use crate::{
+    clusters::Cluster,
+    evaluation::{evaluate, Evaluation, EvaluationSettings},
+    preset::Preset,
+    resources::{rules::Rules, settings::Settings, world::WorldSize},
+};
+
+// Rule sets competing for every parent slot
+const TOURNAMENT_SIZE: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Fitness {
+    // The score of a rule search
+    Interesting,
+    // Clusters alive at the end of the evaluation
+    Clusters,
+    // Mean speed of the clusters
+    Motility,
+    // Mirror symmetry of the largest cluster
+    Symmetry,
+    // Closeness of the largest cluster to the target aspect ratio
+    Shape,
+}
+
+pub struct EvolutionSettings {
+    pub generations: usize,
+    pub population: usize,
+    // Best rule sets carried over into the next generation unchanged
+    pub elite: usize,
+    // Chance of every value of a child to be perturbed, and by how much at most
+    pub mutation_rate: f32,
+    pub mutation_strength: f32,
+    pub fitness: Fitness,
+    // Length divided by width the shape fitness aims for
+    pub target_aspect: f32,
+    pub directory: PathBuf,
+    pub amounts: Option<Vec<usize>>,
+    pub evaluation: EvaluationSettings,
+}
+
+#[derive(Clone, Copy)]
+struct Lineage {
+    parents: Option<(u64, u64)>,
+}
+
+// Evolves a population of random rule sets for a number of generations, logging every rule set
+// with its parents and fitness and saving the best of every generation as a preset
+pub fn evolve<const S: usize>(
+    seed: u64,
+    settings: &Settings,
+    world: &WorldSize,
+    evolution: &EvolutionSettings,
+) -> io::Result<Option<Rules<S>>> {
+    fs::create_dir_all(&evolution.directory)?;
+    let mut lineage_log = BufWriter::new(File::create(evolution.directory.join("lineage.csv"))?);
+    writeln!(lineage_log, "generation,seed,parent_a,parent_b,fitness")?;
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut population: Vec<(Rules<S>, Lineage)> = (0..evolution.population.max(2))
+        .map(|_| {
+            let mut rules = Rules::<S>::from_seed(rng.gen());
+            apply_amounts(&mut rules, &evolution.amounts);
+            (rules, Lineage { parents: None })
+        })
+        .collect();
+
+    let mut best: Option<(Rules<S>, f32)> = None;
+    for generation in 0..evolution.generations {
+        let mut ranked: Vec<(Rules<S>, Lineage, f32)> = population
+            .into_par_iter()
+            .map(|(rules, lineage)| {
+                let result = evaluate(
+                    rules.clone(),
+                    settings.clone(),
+                    *world,
+                    &evolution.evaluation,
+                );
+                let fitness = fitness(&result, evolution.fitness, evolution.target_aspect);
+                (rules, lineage, fitness)
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+        for (rules, lineage, fitness) in &ranked {
+            let (parent_a, parent_b) = match lineage.parents {
+                Some((a, b)) => (a.to_string(), b.to_string()),
+                None => (String::new(), String::new()),
+            };
+            writeln!(
+                lineage_log,
+                "{},{},{},{},{}",
+                generation, rules.seed, parent_a, parent_b, fitness
+            )?;
+        }
+        lineage_log.flush()?;
+
+        let (leader, _, leader_fitness) = &ranked[0];
+        let path = evolution
+            .directory
+            .join(format!("generation-{:03}.json", generation));
+        Preset::new(leader, Some(settings))
+            .save(&path)
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        println!(
+            "Generation {}: best {:?} fitness {:.3} (seed {}), saved to {}",
+            generation,
+            evolution.fitness,
+            leader_fitness,
+            leader.seed,
+            path.display()
+        );
+        if best
+            .as_ref()
+            .is_none_or(|(_, fitness)| leader_fitness > fitness)
+        {
+            best = Some((leader.clone(), *leader_fitness));
+        }
+
+        population = next_generation(&ranked, evolution, &mut rng);
+    }
+
+    Ok(best.map(|(rules, _)| rules))
+}
+
+fn next_generation<const S: usize>(
+    ranked: &[(Rules<S>, Lineage, f32)],
+    evolution: &EvolutionSettings,
+    rng: &mut StdRng,
+) -> Vec<(Rules<S>, Lineage)> {
+    let mut next: Vec<(Rules<S>, Lineage)> = ranked
+        .iter()
+        .take(evolution.elite)
+        .map(|(rules, lineage, _)| (rules.clone(), *lineage))
+        .collect();
+
+    while next.len() < ranked.len() {
+        let a = tournament(ranked, rng);
+        let b = tournament(ranked, rng);
+        let mut child = crossover(a, b, rng);
+        mutate(
+            &mut child,
+            evolution.mutation_rate,
+            evolution.mutation_strength,
+            rng,
+        );
+        apply_amounts(&mut child, &evolution.amounts);
+        next.push((
+            child,
+            Lineage {
+                parents: Some((a.seed, b.seed)),
+            },
+        ));
+    }
+    next
+}
+
+fn tournament<'a, const S: usize>(
+    ranked: &'a [(Rules<S>, Lineage, f32)],
+    rng: &mut StdRng,
+) -> &'a Rules<S> {
+    // Ranked best first, so the lowest index drawn wins
+    let winner = (0..TOURNAMENT_SIZE)
+        .map(|_| rng.gen_range(0..ranked.len()))
+        .min()
+        .unwrap();
+    &ranked[winner].0
+}
+
+// Every attraction comes from either parent, repulsions are inherited per group
+fn crossover<const S: usize>(a: &Rules<S>, b: &Rules<S>, rng: &mut StdRng) -> Rules<S> {
+    let mut child = a.clone();
+    child.seed = rng.gen();
+
+    for i in 0..S {
+        for j in 0..S {
+            if rng.gen_bool(0.5) {
+                child.attractions[i][j] = b.attractions[i][j];
+            }
+        }
+        if rng.gen_bool(0.5) {
+            child.rep_range[i] = b.rep_range[i];
+            child.rep_force[i] = b.rep_force[i];
+        }
+    }
+    child
+}
+
+// Perturbs every attraction and repulsion with a chance of `rate` by up to `strength`
+pub fn mutate<const S: usize>(rules: &mut Rules<S>, rate: f32, strength: f32, rng: &mut impl Rng) {
+    let mut perturb = |value: &mut f32, scale: f32, min: f32, max: f32| {
+        if rng.gen::<f32>() < rate {
+            *value = (*value + rng.gen_range(-strength..=strength) * scale).clamp(min, max);
+        }
+    };
+
+    for i in 0..S {
+        for j in 0..S {
+            perturb(&mut rules.attractions[i][j], 1., -1., 1.);
+        }
+        perturb(&mut rules.rep_range[i], 10., 1., 40.);
+        perturb(&mut rules.rep_force[i], 1., -2., 0.);
+    }
+}
+
+fn apply_amounts<const S: usize>(rules: &mut Rules<S>, amounts: &Option<Vec<usize>>) {
+    if let Some(amounts) = amounts {
+        rules.amount.copy_from_slice(amounts);
+    }
+}
+
+pub fn fitness<const S: usize>(
+    result: &Evaluation<S>,
+    fitness: Fitness,
+    target_aspect: f32,
+) -> f32 {
+    let clusters = &result.clusters.clusters;
+    let largest = clusters.iter().max_by_key(|cluster| cluster.size());
+
+    match fitness {
+        Fitness::Interesting => result.score.total,
+        Fitness::Clusters => clusters.len() as f32,
+        Fitness::Motility => {
+            let speed: f32 = clusters
+                .iter()
+                .map(|cluster| cluster.velocity.length())
+                .sum();
+            speed / clusters.len().max(1) as f32
+        }
+        Fitness::Symmetry => {
+            largest.map_or(0., |cluster| symmetry(&member_positions(result, cluster)))
+        }
+        Fitness::Shape => largest.map_or(0., |cluster| {
+            let aspect = principal_axes(&member_positions(result, cluster)).1;
+            // 1 at the target, falling off with the ratio between the two
+            (-(aspect / target_aspect).ln().abs()).exp()
+        }),
+    }
+}
+
+fn member_positions<const S: usize>(result: &Evaluation<S>, cluster: &Cluster) -> Vec<Vec2> {
+    cluster
+        .members
+        .iter()
+        .filter_map(|entity| result.universe.particle(*entity))
+        .map(|(pos, _, _)| pos)
+        .collect()
+}
+
+// Main axis direction and length divided by width of a point cloud
+fn principal_axes(points: &[Vec2]) -> (Vec2, f32) {
+    let center = points.iter().copied().sum::<Vec2>() / points.len().max(1) as f32;
+    let (mut xx, mut xy, mut yy) = (0., 0., 0.);
+    for point in points {
+        let d = *point - center;
+        xx += d.x * d.x;
+        xy += d.x * d.y;
+        yy += d.y * d.y;
+    }
+
+    // Eigenvalues of the covariance matrix
+    let mean = (xx + yy) / 2.;
+    let spread = (((xx - yy) / 2.).powi(2) + xy * xy).sqrt();
+    let major = mean + spread;
+    let minor = (mean - spread).max(f32::EPSILON);
+
+    let angle = 0.5 * (2. * xy).atan2(xx - yy);
+    (Vec2::from_angle(angle), (major / minor).sqrt())
+}
+
+// 1 when mirroring the points at their main axis maps every point onto another, falling
+// towards 0 as the mirrored points land further from the nearest real one
+fn symmetry(points: &[Vec2]) -> f32 {
+    if points.is_empty() {
+        return 0.;
+    }
+
+    let center = points.iter().copied().sum::<Vec2>() / points.len() as f32;
+    let (axis, _) = principal_axes(points);
+    let radius = points
+        .iter()
+        .map(|point| point.distance(center))
+        .fold(f32::EPSILON, f32::max);
+
+    let error: f32 = points
+        .iter()
+        .map(|point| {
+            let d = *point - center;
+            let mirrored = center + axis * 2. * d.dot(axis) - d;
+            points
+                .iter()
+                .map(|other| other.distance(mirrored))
+                .fold(f32::INFINITY, f32::min)
+        })
+        .sum();
+
+    1. - (error / points.len() as f32 / radius).min(1.)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::Entity;
+
+    use super::*;
+    use crate::{clusters::Clusters, evaluation::Score, universe::Universe};
+
+    // Points on a grid of `columns` by `rows` spaced 1 apart, centered on the origin
+    fn grid(columns: usize, rows: usize) -> Vec<Vec2> {
+        (0..columns * rows)
+            .map(|index| {
+                Vec2::new((index % columns) as f32, (index / columns) as f32)
+                    - Vec2::new(columns as f32 - 1., rows as f32 - 1.) / 2.
+            })
+            .collect()
+    }
+
+    #[test]
+    fn children_inherit_every_value_from_a_parent() {
+        let a = Rules::<4>::from_seed(1);
+        let b = Rules::<4>::from_seed(2);
+        let mut rng = StdRng::seed_from_u64(3);
+
+        let (mut from_a, mut from_b) = (0, 0);
+        for _ in 0..20 {
+            let child = crossover(&a, &b, &mut rng);
+            for i in 0..4 {
+                for j in 0..4 {
+                    let attraction = child.attractions[i][j];
+                    assert!(attraction == a.attractions[i][j] || attraction == b.attractions[i][j]);
+                    if attraction == a.attractions[i][j] {
+                        from_a += 1;
+                    } else {
+                        from_b += 1;
+                    }
+                }
+                // Range and force of a group come from the same parent
+                let repulsion = (child.rep_range[i], child.rep_force[i]);
+                assert!(
+                    repulsion == (a.rep_range[i], a.rep_force[i])
+                        || repulsion == (b.rep_range[i], b.rep_force[i])
+                );
+            }
+            assert_eq!(child.amount, a.amount);
+        }
+        assert!(from_a > 0 && from_b > 0);
+    }
+
+    #[test]
+    fn mutations_stay_within_bounds() {
+        let original = Rules::<3>::from_seed(4);
+        let mut rng = StdRng::seed_from_u64(5);
+
+        let mut unchanged = original.clone();
+        mutate(&mut unchanged, 0., 1., &mut rng);
+        assert_eq!(unchanged.attractions, original.attractions);
+        assert_eq!(unchanged.rep_range, original.rep_range);
+        assert_eq!(unchanged.rep_force, original.rep_force);
+
+        let mut rules = original.clone();
+        for _ in 0..1000 {
+            mutate(&mut rules, 1., 0.5, &mut rng);
+            assert!(rules
+                .attractions
+                .iter()
+                .flatten()
+                .all(|a| (-1. ..=1.).contains(a)));
+            assert!(rules.rep_range.iter().all(|r| (1. ..=40.).contains(r)));
+            assert!(rules.rep_force.iter().all(|f| (-2. ..=0.).contains(f)));
+        }
+        assert_ne!(rules.attractions, original.attractions);
+    }
+
+    #[test]
+    fn principal_axes_follow_the_longer_side() {
+        let (axis, aspect) = principal_axes(&grid(8, 2));
+        assert!(axis.y.abs() < 1e-5 && (axis.x.abs() - 1.).abs() < 1e-5);
+        assert!((aspect - (63f32 / 3.).sqrt()).abs() < 1e-4);
+
+        let (_, square) = principal_axes(&grid(5, 5));
+        assert!((square - 1.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn symmetry_falls_for_lopsided_clusters() {
+        assert_eq!(symmetry(&[]), 0.);
+        assert!((symmetry(&grid(6, 3)) - 1.).abs() < 1e-5);
+
+        // An L shape has no mirror axis along its main axis
+        let mut lopsided = grid(6, 1);
+        lopsided.extend((1..4).map(|y| Vec2::new(-2.5, y as f32)));
+        assert!(symmetry(&lopsided) < 0.9);
+    }
+
+    #[test]
+    fn every_fitness_measures_its_own_kind() {
+        let mut rules = Rules::<2>::from_seed(6);
+        rules.amount = [15; 2];
+        let world = WorldSize {
+            width: 100,
+            height: 100,
+        };
+        let mut universe = Universe::new(rules, Settings::default(), world);
+        universe.step();
+        let entities: Vec<Entity> = universe
+            .particles()
+            .iter()
+            .map(|(entity, _, _, _)| *entity)
+            .collect();
+
+        let cluster = |members: &[Entity], velocity: Vec2| Cluster {
+            id: 0,
+            members: members.to_vec(),
+            composition: vec![members.len(), 0],
+            center: Vec2::ZERO,
+            velocity,
+            born: 0,
+        };
+        let mut clusters = Clusters::default();
+        clusters.clusters = vec![
+            cluster(&entities[..20], Vec2::new(3., 4.)),
+            cluster(&entities[20..], Vec2::new(1., 0.)),
+        ];
+        let result = Evaluation {
+            score: Score {
+                total: 0.75,
+                ..Default::default()
+            },
+            universe,
+            clusters,
+        };
+
+        assert_eq!(fitness(&result, Fitness::Interesting, 2.), 0.75);
+        assert_eq!(fitness(&result, Fitness::Clusters, 2.), 2.);
+        assert_eq!(fitness(&result, Fitness::Motility, 2.), 3.);
+
+        let points = member_positions(&result, &result.clusters.clusters[0]);
+        assert_eq!(points.len(), 20);
+        assert_eq!(fitness(&result, Fitness::Symmetry, 2.), symmetry(&points));
+
+        // Highest for the aspect ratio of the largest cluster itself
+        let aspect = principal_axes(&points).1;
+        let shape = fitness(&result, Fitness::Shape, aspect);
+        assert!((shape - 1.).abs() < 1e-5);
+        assert!(fitness(&result, Fitness::Shape, aspect * 2.) < shape);
+        assert!(fitness(&result, Fitness::Shape, aspect / 2.) < shape);
+    }
+}
@@ -0,0 +1,252 @@
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+use iyes_loopless::prelude::IntoConditionalSystem;
+use serde::Deserialize;
+
+use crate::{
+    config::ConfigReloaded,
+    entity::particle::{Force, GroupId, ParticleMarker, Position},
+    resources::{chunks::Chunks, settings::Settings, tick::Tick},
+};
+
+// How fast the turbulence changes, in noise cells per tick
+const TURBULENCE_SPEED: f32 = 0.005;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    // Pulls every particle along `direction`
+    #[default]
+    Gravity,
+    // Pulls towards `position`, or pushes away with a negative strength
+    Attractor,
+    // Turns around `position`, counterclockwise with a positive strength
+    Vortex,
+    // Flows along `direction`, faster the further a particle is from the line through `position`
+    Shear,
+    // Pushes in directions following a smooth noise with cells of `scale`
+    Turbulence,
+}
+
+// A force acting on every particle of the chosen groups, on top of the forces between them
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct ForceField {
+    pub kind: FieldKind,
+    pub strength: f32,
+    pub position: [f32; 2],
+    pub direction: [f32; 2],
+    // Distance at which an attractor or vortex has lost half its strength, the distance from
+    // the line a shear flow reaches its strength at and the size of turbulence cells
+    pub scale: f32,
+    // Groups the field acts on, every group when empty
+    pub groups: Vec<usize>,
+}
+
+impl Default for ForceField {
+    fn default() -> Self {
+        Self {
+            kind: FieldKind::Gravity,
+            strength: 0.05,
+            position: [0., 0.],
+            direction: [0., -1.],
+            scale: 200.,
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl ForceField {
+    pub fn acts_on(&self, group: usize) -> bool {
+        self.groups.is_empty() || self.groups.contains(&group)
+    }
+
+    // Velocity the field adds to a particle at `pos` in one tick
+    pub fn force(&self, pos: Vec2, tick: u64) -> Vec2 {
+        let center = Vec2::from(self.position);
+        let direction = Vec2::from(self.direction).normalize_or_zero();
+        let scale = self.scale.max(f32::EPSILON);
+        let falloff = |dist: f32| scale / (scale + dist);
+
+        match self.kind {
+            FieldKind::Gravity => direction * self.strength,
+            FieldKind::Attractor => {
+                let to_center = center - pos;
+                to_center.normalize_or_zero() * self.strength * falloff(to_center.length())
+            }
+            FieldKind::Vortex => {
+                let from_center = pos - center;
+                from_center.perp().normalize_or_zero()
+                    * self.strength
+                    * falloff(from_center.length())
+            }
+            FieldKind::Shear => {
+                let distance = (pos - center).dot(direction.perp());
+                direction * self.strength * distance / scale
+            }
+            FieldKind::Turbulence => {
+                let cell = pos / scale;
+                let angle = value_noise(cell.x, cell.y, tick as f32 * TURBULENCE_SPEED) * TAU * 2.;
+                Vec2::from_angle(angle) * self.strength
+            }
+        }
+    }
+}
+
+#[derive(Resource, Default, Clone)]
+pub struct ForceFields(pub Vec<ForceField>);
+
+pub struct FieldPlugin;
+
+impl Plugin for FieldPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(apply_field_config.after("config"))
+            .add_system(
+                apply_fields
+                    .run_if_resource_exists::<Chunks>()
+                    .label("fields")
+                    .label("forces")
+                    .after("temperature"),
+            );
+    }
+}
+
+fn apply_field_config(mut fields: ResMut<ForceFields>, mut reloads: EventReader<ConfigReloaded>) {
+    if let Some(ConfigReloaded(config)) = reloads.iter().last() {
+        if let Some(new_fields) = &config.fields {
+            if *new_fields != fields.0 {
+                fields.0 = new_fields.clone();
+            }
+        }
+    }
+}
+
+fn apply_fields(
+    fields: Res<ForceFields>,
+    settings: Res<Settings>,
+    tick: Res<Tick>,
+    mut particle_query: Query<(&mut Force, &Position, &GroupId), With<ParticleMarker>>,
+) {
+    if fields.0.is_empty() || !settings.forces.fields {
+        return;
+    }
+
+    particle_query.par_for_each_mut(64, |(mut force, pos, id)| {
+        for field in fields.0.iter().filter(|field| field.acts_on(id.0)) {
+            force.0 += field.force(pos.0, tick.0);
+        }
+    });
+}
+
+// Smooth noise between 0 and 1, interpolated between random values on a 3D grid
+fn value_noise(x: f32, y: f32, z: f32) -> f32 {
+    let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+    let smooth = |t: f32| t * t * (3. - 2. * t);
+    let (tx, ty, tz) = (smooth(x - x0), smooth(y - y0), smooth(z - z0));
+    let (ix, iy, iz) = (x0 as i32, y0 as i32, z0 as i32);
+
+    let corner = |dx: i32, dy: i32, dz: i32| lattice(ix + dx, iy + dy, iz + dz);
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+    let front = lerp(
+        lerp(corner(0, 0, 0), corner(1, 0, 0), tx),
+        lerp(corner(0, 1, 0), corner(1, 1, 0), tx),
+        ty,
+    );
+    let back = lerp(
+        lerp(corner(0, 0, 1), corner(1, 0, 1), tx),
+        lerp(corner(0, 1, 1), corner(1, 1, 1), tx),
+        ty,
+    );
+    lerp(front, back, tz)
+}
+
+// Random value between 0 and 1 for a grid point, always the same for the same point
+fn lattice(x: i32, y: i32, z: i32) -> f32 {
+    let mut hash = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0x5bd1_e995);
+    hash ^= hash >> 15;
+    hash as f32 / u32::MAX as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(kind: FieldKind) -> ForceField {
+        ForceField {
+            kind,
+            strength: 2.,
+            position: [10., 0.],
+            direction: [0., 3.],
+            scale: 100.,
+            ..default()
+        }
+    }
+
+    fn assert_near(a: Vec2, b: Vec2) {
+        assert!(a.abs_diff_eq(b, 1e-5), "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn gravity_pulls_along_its_direction_everywhere() {
+        let gravity = field(FieldKind::Gravity);
+        assert_near(gravity.force(Vec2::ZERO, 0), Vec2::new(0., 2.));
+        assert_near(gravity.force(Vec2::new(-500., 80.), 99), Vec2::new(0., 2.));
+    }
+
+    #[test]
+    fn attractors_and_vortices_lose_half_their_strength_at_scale() {
+        let attractor = field(FieldKind::Attractor);
+        assert_near(attractor.force(Vec2::new(110., 0.), 0), Vec2::new(-1., 0.));
+        assert_near(attractor.force(Vec2::new(10., 0.), 0), Vec2::ZERO);
+
+        let vortex = field(FieldKind::Vortex);
+        // Counterclockwise with a positive strength
+        assert_near(vortex.force(Vec2::new(110., 0.), 0), Vec2::new(0., 1.));
+        assert_near(vortex.force(Vec2::new(10., -100.), 0), Vec2::new(1., 0.));
+    }
+
+    #[test]
+    fn shear_grows_with_the_distance_from_its_line() {
+        let shear = field(FieldKind::Shear);
+        assert_near(shear.force(Vec2::new(10., 50.), 0), Vec2::ZERO);
+        assert_near(shear.force(Vec2::new(-40., 0.), 0), Vec2::new(0., 1.));
+        assert_near(shear.force(Vec2::new(60., 0.), 0), Vec2::new(0., -1.));
+    }
+
+    #[test]
+    fn turbulence_keeps_its_strength() {
+        let turbulence = field(FieldKind::Turbulence);
+        for (x, y, tick) in [(0., 0., 0), (-37.5, 12., 10), (400., -900., 12345)] {
+            let force = turbulence.force(Vec2::new(x, y), tick);
+            assert!((force.length() - 2.).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn value_noise_is_smooth_and_between_0_and_1() {
+        for step in 0..1000 {
+            let t = step as f32 * 0.037 - 18.;
+            let noise = value_noise(t, t * 0.7, -t * 0.3);
+            assert!((0. ..=1.).contains(&noise));
+
+            let next = value_noise(t + 0.001, t * 0.7, -t * 0.3);
+            assert!((next - noise).abs() < 0.01);
+        }
+        // On the grid it is the value of the grid point
+        assert_eq!(value_noise(3., -2., 5.), lattice(3, -2, 5));
+    }
+
+    #[test]
+    fn fields_act_on_their_groups() {
+        let mut gravity = field(FieldKind::Gravity);
+        assert!(gravity.acts_on(0) && gravity.acts_on(7));
+        gravity.groups = vec![1, 3];
+        assert!(gravity.acts_on(3) && !gravity.acts_on(0));
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ConfigReloaded,
+    entity::particle::{GroupId, ParticleIndex, ParticleMarker},
+    resources::{rules::Rules, tick::Tick},
+};
+
+// Hue shift in degrees between two colors of the genome palette
+const HUE_STEP: f32 = 15.;
+
+#[derive(Resource, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct GenomeSettings {
+    // Largest deviation of a new genome from the rules of its group
+    pub initial_noise: f32,
+    // Chance of every value to be perturbed when a particle divides, and by how much at most
+    pub mutation_rate: f32,
+    pub mutation_strength: f32,
+    // Hue shift in degrees per unit the attractions deviate from the rules on average
+    pub color_variation: f32,
+}
+
+impl Default for GenomeSettings {
+    fn default() -> Self {
+        Self {
+            initial_noise: 0.05,
+            mutation_rate: 0.1,
+            mutation_strength: 0.1,
+            color_variation: 300.,
+        }
+    }
+}
+
+// The row of the rules a particle interacts by, carried by the particle itself
+#[derive(Component, Clone)]
+pub struct Genome<const S: usize> {
+    pub attractions: [f32; S],
+    pub rep_range: f32,
+    pub rep_force: f32,
+}
+
+impl<const S: usize> Genome<S> {
+    pub fn from_rules(rules: &Rules<S>, group: usize) -> Self {
+        Self {
+            attractions: rules.attractions[group],
+            rep_range: rules.rep_range[group],
+            rep_force: rules.rep_force[group],
+        }
+    }
+
+    // Perturbs every value with a chance of `rate` by up to `strength`. Values stay within the
+    // usual range of their kind, widened to take in every value `rules` has of it
+    pub fn mutate(&mut self, rules: &Rules<S>, rate: f32, strength: f32, rng: &mut impl Rng) {
+        let span = |values: &mut dyn Iterator<Item = &f32>, min: f32, max: f32| {
+            values.fold((min, max), |(min, max), value| {
+                (min.min(*value), max.max(*value))
+            })
+        };
+        let attraction_bounds = span(&mut rules.attractions.iter().flatten(), -1., 1.);
+        let rep_range_bounds = span(&mut rules.rep_range.iter(), 1., 40.);
+        let rep_force_bounds = span(&mut rules.rep_force.iter(), -2., 0.);
+
+        let mut perturb = |value: &mut f32, scale: f32, (min, max): (f32, f32)| {
+            if rng.gen::<f32>() < rate {
+                *value = (*value + rng.gen_range(-strength..=strength) * scale).clamp(min, max);
+            }
+        };
+
+        for attraction in self.attractions.iter_mut() {
+            perturb(attraction, 1., attraction_bounds);
+        }
+        perturb(&mut self.rep_range, 10., rep_range_bounds);
+        perturb(&mut self.rep_force, 1., rep_force_bounds);
+    }
+
+    // Steps of `HUE_STEP` the group color is shifted by, following how far the attractions
+    // drifted from the rules either way
+    pub fn hue_steps(&self, rules: &Rules<S>, group: usize, variation: f32) -> i32 {
+        let drift: f32 = self
+            .attractions
+            .iter()
+            .zip(rules.attractions[group])
+            .map(|(own, original)| (own - original).abs())
+            .sum::<f32>()
+            / S as f32;
+
+        (drift * variation / HUE_STEP).round() as i32
+    }
+}
+
+// `Genome` with its const sized array flattened, so snapshots don't depend on the group count
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GenomeSnapshot {
+    pub attractions: Vec<f32>,
+    pub rep_range: f32,
+    pub rep_force: f32,
+}
+
+impl<const S: usize> From<&Genome<S>> for GenomeSnapshot {
+    fn from(genome: &Genome<S>) -> Self {
+        Self {
+            attractions: genome.attractions.to_vec(),
+            rep_range: genome.rep_range,
+            rep_force: genome.rep_force,
+        }
+    }
+}
+
+impl GenomeSnapshot {
+    pub fn to_genome<const S: usize>(&self) -> Option<Genome<S>> {
+        Some(Genome {
+            attractions: self.attractions.clone().try_into().ok()?,
+            rep_range: self.rep_range,
+            rep_force: self.rep_force,
+        })
+    }
+}
+
+// Genome of the particle a new particle divided from, turned into a mutated genome of its own
+#[derive(Component)]
+pub struct InheritedGenome<const S: usize>(pub Genome<S>);
+
+// Materials shared by every particle of a group whose genome shifts its color by the same steps
+#[derive(Resource, Default)]
+pub struct GenomePalette(HashMap<(usize, i32), Handle<ColorMaterial>>);
+
+impl GenomePalette {
+    fn material<const S: usize>(
+        &mut self,
+        rules: &Rules<S>,
+        group: usize,
+        steps: i32,
+        materials: &mut Assets<ColorMaterial>,
+    ) -> Handle<ColorMaterial> {
+        self.0
+            .entry((group, steps))
+            .or_insert_with(|| {
+                let [hue, saturation, lightness, alpha] = rules.colors[group].as_hsla_f32();
+                let hue = (hue + steps as f32 * HUE_STEP).rem_euclid(360.);
+                materials.add(ColorMaterial::from(Color::hsla(
+                    hue, saturation, lightness, alpha,
+                )))
+            })
+            .clone()
+    }
+}
+
+// Random numbers for the genome a particle gets at a tick, the same for the same rules seed,
+// tick and particle so runs with genomes replay exactly
+fn genome_rng(seed: u64, tick: u64, index: ParticleIndex) -> StdRng {
+    StdRng::seed_from_u64(
+        seed.rotate_left(32)
+            ^ tick.wrapping_mul(0xd6e8_feb8_6659_fd93)
+            ^ index.0.wrapping_mul(0x94d0_49bb_1331_11eb),
+    )
+}
+
+// Every particle interacts by its own copy of the rules, which mutates whenever it divides
+pub struct GenomePlugin<const S: usize>;
+
+impl<const S: usize> Plugin for GenomePlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GenomePalette>()
+            .add_system(apply_genome_config.after("config"))
+            .add_system(give_genomes::<S>.before("update"))
+            .add_system(color_genomes::<S>.before("update"));
+    }
+}
+
+fn apply_genome_config(
+    mut settings: ResMut<GenomeSettings>,
+    mut reloads: EventReader<ConfigReloaded>,
+) {
+    if let Some(ConfigReloaded(config)) = reloads.iter().last() {
+        if let Some(new_settings) = &config.genome {
+            if *new_settings != *settings {
+                *settings = new_settings.clone();
+            }
+        }
+    }
+}
+
+type NewParticleQuery<'w, 's, const S: usize> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static ParticleIndex,
+        &'static GroupId,
+        Option<&'static InheritedGenome<S>>,
+    ),
+    (With<ParticleMarker>, Without<Genome<S>>),
+>;
+
+// New particles, spawned, converted or divided, get a genome. Restored particles bring theirs
+fn give_genomes<const S: usize>(
+    mut commands: Commands,
+    rules: Res<Rules<S>>,
+    settings: Res<GenomeSettings>,
+    tick: Res<Tick>,
+    particle_query: NewParticleQuery<S>,
+) {
+    for (entity, index, id, inherited) in particle_query.iter() {
+        let mut rng = genome_rng(rules.seed, tick.0, *index);
+        let genome = match inherited {
+            Some(InheritedGenome(parent)) => {
+                let mut genome = parent.clone();
+                genome.mutate(
+                    &rules,
+                    settings.mutation_rate,
+                    settings.mutation_strength,
+                    &mut rng,
+                );
+                genome
+            }
+            None => {
+                let mut genome = Genome::from_rules(&rules, id.0);
+                genome.mutate(&rules, 1., settings.initial_noise, &mut rng);
+                genome
+            }
+        };
+
+        commands
+            .entity(entity)
+            .remove::<InheritedGenome<S>>()
+            .insert(genome);
+    }
+}
+
+type NewGenomeQuery<'w, 's, const S: usize> = Query<
+    'w,
+    's,
+    (
+        &'static Genome<S>,
+        &'static GroupId,
+        &'static mut Handle<ColorMaterial>,
+    ),
+    (With<ParticleMarker>, Changed<Genome<S>>),
+>;
+
+// Particles with a new genome get the palette color matching it
+fn color_genomes<const S: usize>(
+    rules: Res<Rules<S>>,
+    settings: Res<GenomeSettings>,
+    mut palette: ResMut<GenomePalette>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut particle_query: NewGenomeQuery<S>,
+) {
+    // New rules come with new group colors
+    if rules.is_changed() || settings.is_changed() {
+        palette.0.clear();
+    }
+
+    for (genome, id, mut material) in particle_query.iter_mut() {
+        let steps = genome.hue_steps(&rules, id.0, settings.color_variation);
+        *material = palette.material(&rules, id.0, steps, &mut materials);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mutations_keep_values_of_the_rules_outside_the_usual_range() {
+        let mut rules = Rules::<2>::from_seed(3);
+        rules.rep_range = [60.; 2];
+        rules.rep_force = [0.5; 2];
+        rules.attractions[0] = [1.5, -3.];
+
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let mut genome = Genome::from_rules(&rules, 0);
+            genome.mutate(&rules, 1., 0.01, &mut rng);
+            assert!((genome.rep_range - 60.).abs() <= 0.1);
+            assert!((genome.rep_force - 0.5).abs() <= 0.01);
+            assert!((genome.attractions[0] - 1.5).abs() <= 0.01);
+            assert!((genome.attractions[1] + 3.).abs() <= 0.01);
+        }
+    }
+
+    #[test]
+    fn mutations_stay_within_the_usual_range() {
+        let rules = Rules::<3>::from_seed(3);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut genome = Genome::from_rules(&rules, 1);
+        for _ in 0..1000 {
+            genome.mutate(&rules, 1., 0.5, &mut rng);
+            assert!(genome.attractions.iter().all(|a| (-1. ..=1.).contains(a)));
+            assert!((1. ..=40.).contains(&genome.rep_range));
+            assert!((-2. ..=0.).contains(&genome.rep_force));
+        }
+    }
+
+    #[test]
+    fn drift_either_way_shifts_the_hue() {
+        let mut rules = Rules::<2>::from_seed(3);
+        rules.attractions[0] = [0., 0.];
+        let mut genome = Genome::from_rules(&rules, 0);
+        assert_eq!(genome.hue_steps(&rules, 0, 300.), 0);
+
+        // Opposite drifts don't cancel out
+        genome.attractions = [0.5, -0.5];
+        assert_eq!(genome.hue_steps(&rules, 0, 300.), 10);
+        genome.attractions = [-0.5, -0.5];
+        assert_eq!(genome.hue_steps(&rules, 0, 300.), 10);
+    }
+}
@@ -0,0 +1,100 @@
+use bevy::prelude::*;
+use leafwing_input_manager::{
+    prelude::{ActionState, InputMap},
+    Actionlike,
+};
+
+use crate::resources::actions::{describe_input, Action};
+
+// Built into the binary, so the overlay can be read wherever the game is started from
+const HELP_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSansMono.ttf");
+
+const HELP_FONT_SIZE: f32 = 14.;
+
+#[derive(Component)]
+struct HelpOverlay;
+
+#[derive(Component)]
+struct HelpText;
+
+pub struct HelpPlugin;
+
+impl Plugin for HelpPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(setup_help).add_system(help_controls);
+    }
+}
+
+fn setup_help(mut commands: Commands, mut fonts: ResMut<Assets<Font>>) {
+    let font = fonts.add(Font::try_from_bytes(HELP_FONT.to_vec()).expect("help font is valid"));
+
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(10.),
+                    top: Val::Px(10.),
+                    ..default()
+                },
+                padding: UiRect::all(Val::Px(8.)),
+                ..default()
+            },
+            background_color: Color::rgba(0., 0., 0., 0.7).into(),
+            visibility: Visibility { is_visible: false },
+            ..default()
+        })
+        .insert(HelpOverlay)
+        .with_children(|parent| {
+            parent
+                .spawn(TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font,
+                        font_size: HELP_FONT_SIZE,
+                        color: Color::WHITE,
+                    },
+                ))
+                .insert(HelpText);
+        });
+}
+
+// One line per action with every input currently bound to it
+pub fn help_text(input_map: &InputMap<Action>) -> String {
+    Action::variants()
+        .map(|action| {
+            let inputs: Vec<String> = input_map.get(action).iter().map(describe_input).collect();
+            let inputs = if inputs.is_empty() {
+                "unbound".to_string()
+            } else {
+                inputs.join(", ")
+            };
+            format!("{:?}: {}", action, inputs)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn help_controls(
+    action_query: Query<(&ActionState<Action>, &InputMap<Action>)>,
+    mut overlay_query: Query<&mut Visibility, With<HelpOverlay>>,
+    mut text_query: Query<&mut Text, With<HelpText>>,
+) {
+    let (action_state, input_map) = action_query.single();
+
+    if !action_state.just_pressed(Action::ToggleHelp) {
+        return;
+    }
+
+    for mut visibility in overlay_query.iter_mut() {
+        visibility.is_visible = !visibility.is_visible;
+        if !visibility.is_visible {
+            continue;
+        }
+
+        let help = help_text(input_map);
+        for mut text in text_query.iter_mut() {
+            text.sections[0].value = help.clone();
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera::MousePosition,
+    entity::particle::{Force, ParticleMarker, Position},
+    replay::ReplayPlayer,
+    resources::{actions::Action, rules::Rules, settings::Settings, world::WorldSize},
+    simulation::{spawn_particle, GroupAssets},
+    snapshot::RulesSnapshot,
+};
+
+const FORCE_TOOL_RADIUS: f32 = 100.0;
+const FORCE_TOOL_STRENGTH: f32 = 1.0;
+const SPAWN_TOOL_COUNT: usize = 20;
+
+// Every change a user makes to a running simulation goes through this event, so replays can
+// record and re-apply them at the exact same tick
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Intervention {
+    SetRules {
+        seed: u64,
+        rules: RulesSnapshot,
+    },
+    SetSettings(Settings),
+    Force {
+        position: [f32; 2],
+        radius: f32,
+        strength: f32,
+    },
+    Spawn {
+        position: [f32; 2],
+        group: usize,
+        count: usize,
+    },
+}
+
+pub fn intervention_controls<const S: usize>(
+    mouse_position: Res<MousePosition>,
+    player: Res<ReplayPlayer>,
+    mut interventions: EventWriter<Intervention>,
+    action_query: Query<&ActionState<Action>>,
+) {
+    // The replay is the only source of interventions while it plays
+    if player.is_playing() {
+        return;
+    }
+
+    let action_state = action_query.single();
+
+    if action_state.just_pressed(Action::Restart) {
+        let rules = Rules::<S>::random();
+        interventions.send(Intervention::SetRules {
+            seed: rules.seed,
+            rules: RulesSnapshot::from(&rules),
+        });
+    }
+
+    if action_state.pressed(Action::ForceTool) {
+        interventions.send(Intervention::Force {
+            position: mouse_position.world.to_array(),
+            radius: FORCE_TOOL_RADIUS,
+            strength: FORCE_TOOL_STRENGTH,
+        });
+    }
+
+    if action_state.just_pressed(Action::SpawnTool) {
+        interventions.send(Intervention::Spawn {
+            position: mouse_position.world.to_array(),
+            group: rand::thread_rng().gen_range(0..S),
+            count: SPAWN_TOOL_COUNT,
+        });
+    }
+}
+
+pub fn apply_interventions<const S: usize>(
+    mut commands: Commands,
+    (settings, world): (Res<Settings>, Res<WorldSize>),
+    group_assets: Option<Res<GroupAssets>>,
+    mut interventions: EventReader<Intervention>,
+    mut particle_query: Query<(&mut Force, &Position), With<ParticleMarker>>,
+) {
+    for intervention in interventions.iter() {
+        match intervention {
+            Intervention::SetRules { seed, rules } => match rules.to_rules::<S>(*seed) {
+                Ok(rules) => commands.insert_resource(rules),
+                Err(err) => error!("Ignoring rules intervention: {}", err),
+            },
+            Intervention::SetSettings(settings) => commands.insert_resource(settings.clone()),
+            Intervention::Force {
+                position,
+                radius,
+                strength,
+            } => {
+                // Still recorded in replays while off, so turning it back on replays it again
+                if !settings.forces.tools {
+                    continue;
+                }
+
+                let position = Vec2::from(*position);
+                for (mut force, pos) in particle_query.iter_mut() {
+                    let vec = position - pos.0;
+                    let dist = vec.length();
+                    if dist == 0.0 || dist > *radius {
+                        continue;
+                    }
+
+                    force.0 += vec / dist * *strength * (1.0 - dist / radius);
+                }
+            }
+            Intervention::Spawn {
+                position,
+                group,
+                count,
+            } => {
+                let Some((mesh, material)) = group_assets
+                    .as_ref()
+                    .and_then(|assets| assets.0.get(*group))
+                else {
+                    continue;
+                };
+
+                // Outside the world is where the universes next to it are
+                let center = Vec2::from(*position);
+                if center.abs().cmpgt(world.bounds()).any() {
+                    continue;
+                }
+
+                // Sunflower pattern, so spawning needs no randomness
+                for i in 0..*count {
+                    let angle = i as f32 * PI * (3.0 - 5f32.sqrt());
+                    let offset = Vec2::new(angle.cos(), angle.sin()) * 2.0 * (i as f32).sqrt();
+                    spawn_particle(
+                        &mut commands,
+                        &settings,
+                        mesh,
+                        material,
+                        center + offset,
+                        Vec2::ZERO,
+                        *group,
+                    );
+                }
+            }
+        }
+    }
+}
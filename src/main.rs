@@ -1,107 +1,513 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
 use bevy::{
+    app::{ScheduleRunnerPlugin, ScheduleRunnerSettings},
     core_pipeline::{bloom::BloomSettings, clear_color::ClearColorConfig},
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
     prelude::{
         default,
         shape::{self, Box},
         App, AssetServer, Assets, Camera, Camera2d, Camera2dBundle, Camera3dBundle, ClearColor,
-        Color, Commands, ImagePlugin, IntoSystemDescriptor, Mesh, PluginGroup, Res, ResMut,
-        Transform, Vec2, Vec3,
+        Commands, ImagePlugin, IntoSystemDescriptor, Mesh, PluginGroup, Res, ResMut, Transform,
+        Vec2, Vec3,
     },
-    render::texture::ImageSampler,
+    render::{settings::WgpuSettings, texture::ImageSampler},
     sprite::{ColorMaterial, MaterialMesh2dBundle},
-    window::{CursorGrabMode, WindowDescriptor, WindowPlugin},
+    window::{CursorGrabMode, WindowDescriptor, WindowMode, WindowPlugin},
+    winit::WinitPlugin,
     DefaultPlugins,
 };
 use bevy_rapier2d::prelude::{
     AdditionalMassProperties, Ccd, Damping, ExternalForce, NoUserData, RapierConfiguration,
     RapierPhysicsPlugin, RigidBody, Sleeping, Vect, Velocity,
 };
-use camera::{camera_movement, controls, CameraZoom, MousePosition};
-use chunking::sort_into_chunks;
+use breeding::{Breeding, BreedingPlugin, BREEDING_AMOUNT};
+use camera::{
+    camera_bookmarks, camera_follow, camera_framing, camera_movement, camera_smoothing,
+    cursor_movement, follow_controls, CameraBookmarks, CameraFollow, CameraTarget, CameraZoom,
+    MainCamera, MousePosition,
+};
+use capture::{CapturePlugin, ScheduledScreenshots};
+use clap::Parser;
+use cli::{Cli, DEFAULT_GROUPS, MAX_GROUPS};
+use clusters::ClusterOverlayPlugin;
+use config::{ConfigFile, ConfigPlugin};
+use conversion::{ConversionPlugin, Conversions};
+use ecology::EcologyPlugin;
 use entity::particle::{Particle, ParticleMarker};
+use evaluation::EvaluationSettings;
+use evolution::{evolve, EvolutionSettings};
+use fields::ForceFields;
+use genome::GenomePlugin;
+use help::HelpPlugin;
+use interventions::intervention_controls;
 use iyes_loopless::prelude::IntoConditionalSystem;
 use leafwing_input_manager::prelude::InputManagerPlugin;
-use physics::apply_velocity;
-use rand::{random, Rng};
+use metrics::MetricsPlugin;
+use minimap::MinimapPlugin;
+use preset::Preset;
+use rand::random;
+use recording::{RecordingPlugin, ScheduledRecording};
+use replay::{
+    advance_replay, play_replay, record_interventions, record_step, replay_controls, Replay,
+    ReplayPlayer, ReplayRecorder, ReplaySettings,
+};
 use resources::{
     actions::{action_setup, Action},
     chunks::Chunks,
     rules::Rules,
     settings::Settings,
+    tick::Tick,
 };
-use simulation::{configure, update_edge, update_rules};
+use search::{search, SearchSettings};
+use simulation::{exit_after_steps, NextParticleIndex, SimulationPlugin, StepLimit};
+use snapshot::{quickload, quicksave, PendingRestore, Snapshot, SnapshotSettings};
+use universe::{Universe, UniverseBundle, UniversePlugin};
 
+pub mod breeding;
 pub mod camera;
+pub mod capture;
 pub mod chunking;
+pub mod cli;
+pub mod clusters;
+pub mod config;
+pub mod conversion;
+pub mod ecology;
 pub mod entity;
+pub mod evaluation;
+pub mod evolution;
+pub mod fields;
+pub mod genome;
+pub mod help;
+pub mod interventions;
+pub mod metrics;
+pub mod minimap;
 pub mod physics;
+pub mod preset;
+pub mod recording;
+pub mod replay;
 pub mod resources;
+pub mod search;
 pub mod simulation;
+pub mod snapshot;
+pub mod universe;
 
 const WORLD_WIDTH: usize = 800;
 const WORLD_HEIGHT: usize = 600;
 
-const SIZE: usize = 4;
-
 fn main() {
-    App::new()
+    let cli = Cli::parse();
+
+    let snapshot = cli.snapshot.as_ref().map(|path| {
+        Snapshot::load(path).unwrap_or_else(|err| exit_with_error("snapshot", path, err))
+    });
+    let replay = cli
+        .replay
+        .as_ref()
+        .map(|path| Replay::load(path).unwrap_or_else(|err| exit_with_error("replay", path, err)));
+    let preset = cli
+        .rules
+        .as_ref()
+        .map(|path| Preset::load(path).unwrap_or_else(|err| exit_with_error("rules", path, err)));
+
+    // Files bring their own group count, the simulation is compiled for each one
+    let file_groups = snapshot
+        .as_ref()
+        .map(|snapshot| ("snapshot", snapshot.rules.amount.len()))
+        .or_else(|| {
+            replay
+                .as_ref()
+                .map(|replay| ("replay", replay.initial.rules.amount.len()))
+        })
+        .or_else(|| {
+            preset
+                .as_ref()
+                .map(|preset| ("rules", preset.rules.amount.len()))
+        });
+    let groups = match (file_groups, cli.groups) {
+        (Some((kind, found)), Some(given)) if found != given => {
+            eprintln!(
+                "--groups {} disagrees with the {} groups of the {}",
+                given, found, kind
+            );
+            std::process::exit(1);
+        }
+        (Some((_, found)), _) => found,
+        (None, given) => given.unwrap_or(DEFAULT_GROUPS),
+    };
+
+    match groups {
+        1 => run::<1>(cli, snapshot, replay, preset),
+        2 => run::<2>(cli, snapshot, replay, preset),
+        3 => run::<3>(cli, snapshot, replay, preset),
+        4 => run::<4>(cli, snapshot, replay, preset),
+        5 => run::<5>(cli, snapshot, replay, preset),
+        6 => run::<6>(cli, snapshot, replay, preset),
+        7 => run::<7>(cli, snapshot, replay, preset),
+        8 => run::<8>(cli, snapshot, replay, preset),
+        _ => {
+            eprintln!(
+                "Between 1 and {} groups are supported, not {}",
+                MAX_GROUPS, groups
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn exit_with_error(kind: &str, path: &Path, err: impl Display) -> ! {
+    eprintln!("Failed to load {} {}: {}", kind, path.display(), err);
+    std::process::exit(1);
+}
+
+fn run<const S: usize>(
+    cli: Cli,
+    snapshot: Option<Snapshot>,
+    replay: Option<Replay>,
+    preset: Option<Preset>,
+) {
+    let mut rules = match (&preset, cli.seed) {
+        (Some(preset), _) => preset
+            .rules
+            .to_rules::<S>(preset.seed)
+            .unwrap_or_else(|err| exit_with_error("rules", cli.rules.as_ref().unwrap(), err)),
+        (None, Some(seed)) => Rules::<S>::from_seed(seed),
+        (None, None) => Rules::<S>::random(),
+    };
+    let amounts = cli.amounts(S).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    if let Some(amounts) = &amounts {
+        rules.amount.copy_from_slice(amounts);
+    }
+
+    let config = ConfigFile::load(&cli.config)
+        .unwrap_or_else(|err| exit_with_error("configuration", &cli.config, err));
+
+    let mut settings = preset
+        .and_then(|preset| preset.settings)
+        .or_else(|| config.settings.clone())
+        .unwrap_or_default();
+    let mut world = config.world.unwrap_or_default();
+    cli.apply_world(&mut world);
+    let mut restore = PendingRestore::default();
+    let mut bookmarks = CameraBookmarks::default();
+    let mut player = ReplayPlayer::default();
+
+    if let Some(snapshot) = snapshot {
+        rules = snapshot
+            .rules
+            .to_rules::<S>(snapshot.seed)
+            .unwrap_or_else(|err| exit_with_error("snapshot", cli.snapshot.as_ref().unwrap(), err));
+        settings = snapshot.settings.clone();
+        world = snapshot.world();
+        bookmarks = CameraBookmarks::from(snapshot.camera_bookmarks.as_slice());
+        restore.0 = Some(snapshot);
+    }
+
+    // Settings from the command line apply on top of presets and snapshots
+    cli.apply_settings(&mut settings);
+
+    if let Some(replay) = replay {
+        rules = replay
+            .initial
+            .rules
+            .to_rules::<S>(replay.initial.seed)
+            .unwrap_or_else(|err| exit_with_error("replay", cli.replay.as_ref().unwrap(), err));
+        // Replays only reproduce with their own settings
+        settings = Settings {
+            deterministic: true,
+            ..replay.initial.settings.clone()
+        };
+        world = replay.initial.world();
+        player = ReplayPlayer::new(&replay);
+        restore.0 = Some(replay.initial);
+    }
+
+    if let Some(candidates) = cli.search {
+        let search_settings = SearchSettings {
+            candidates,
+            top: cli.top,
+            directory: cli.presets.clone(),
+            amounts,
+            evaluation: EvaluationSettings {
+                ticks: cli.eval_ticks,
+                ..default()
+            },
+        };
+        search::<S>(
+            cli.seed.unwrap_or_else(random),
+            &settings,
+            &world,
+            &search_settings,
+        );
+        return;
+    }
+
+    if let Some(generations) = cli.evolve {
+        let evolution_settings = EvolutionSettings {
+            generations,
+            population: cli.population,
+            elite: cli.elite,
+            mutation_rate: cli.mutation_rate,
+            mutation_strength: cli.mutation_strength,
+            fitness: cli.fitness,
+            target_aspect: cli.target_aspect,
+            directory: cli.evolution_dir.clone(),
+            amounts,
+            evaluation: EvaluationSettings {
+                ticks: cli.eval_ticks,
+                ..default()
+            },
+        };
+        let seed = cli.seed.unwrap_or_else(random);
+        if let Err(err) = evolve::<S>(seed, &settings, &world, &evolution_settings) {
+            eprintln!(
+                "Evolution failed writing to {}: {}",
+                evolution_settings.directory.display(),
+                err
+            );
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(universes) = cli.breed {
+        if amounts.is_none() {
+            rules.amount = [BREEDING_AMOUNT; S];
+        }
+
+        App::new()
+            .insert_resource(Breeding::new(
+                rules,
+                settings,
+                world,
+                universes,
+                cli.breeding_dir.clone(),
+            ))
+            .insert_resource(config.input.clone().unwrap_or_default())
+            .add_plugins(DefaultPlugins.set(window_plugin(&cli)))
+            .add_plugin(InputManagerPlugin::<Action>::default())
+            .add_plugin(BreedingPlugin::<S>)
+            .add_startup_system(action_setup)
+            .run();
+        return;
+    }
+
+    let rules_seed = rules.seed;
+    let fields = ForceFields(config.fields.clone().unwrap_or_default());
+    let mut app = App::new();
+    if !cli.universes.is_empty() {
+        for (index, overrides) in cli.universes.iter().enumerate() {
+            let mut universe = Universe::<S>::app(rules.clone(), overrides.apply(&settings), world);
+            let metrics = cli
+                .metrics
+                .as_ref()
+                .map(|path| path.with_extension(format!("universe{}.csv", index + 1)));
+            universe.insert_resource(fields.clone());
+            add_optional_plugins::<S>(&mut universe, &cli, &config, rules_seed, metrics);
+
+            app.world.spawn(UniverseBundle::new(
+                Universe::<S>::from_app(universe),
+                Universe::<S>::offset_in_row(index, &world),
+            ));
+        }
+        app.add_plugin(UniversePlugin::<S>);
+    }
+
+    app
         //.insert_resource(RapierConfiguration {
         //    gravity: Vect::ZERO,
         //    ..default()
         //})
-        .insert_resource(Rules::<SIZE>::random())
-        .insert_resource(Settings::default())
+        .insert_resource(rules)
+        .insert_resource(settings)
+        .insert_resource(world)
+        .insert_resource(restore)
+        .insert_resource(SnapshotSettings::default())
+        .insert_resource(player)
+        .insert_resource(ReplayRecorder::default())
+        .insert_resource(ReplaySettings::default())
         .insert_resource(MousePosition::default())
-        .insert_resource(CameraZoom::default())
-        .insert_resource(ClearColor(Color::hex("2596be").unwrap()))
+        .insert_resource(Tick::default())
+        .init_resource::<NextParticleIndex>()
+        .insert_resource(bookmarks)
+        .insert_resource(ScheduledScreenshots(cli.screenshot_at.clone()))
+        .insert_resource(config.input.clone().unwrap_or_default())
+        // Behind the particles in the window and in captures without one
+        .insert_resource(ClearColor(
+            config.rendering.clone().unwrap_or_default().background(),
+        ))
+        .insert_resource(fields);
+
+    if cli.headless {
+        app.insert_resource(WgpuSettings {
+            backends: None,
+            ..default()
+        })
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::ZERO))
         .add_plugins(
             DefaultPlugins
                 .set(WindowPlugin {
-                    window: WindowDescriptor {
-                        title: "Particle Life".into(),
-                        resizable: true,
-                        cursor_visible: true,
-                        cursor_grab_mode: CursorGrabMode::None,
-                        ..default()
-                    },
-                    exit_on_all_closed: true,
+                    add_primary_window: false,
+                    exit_on_all_closed: false,
                     ..default()
                 })
-                .set(ImagePlugin {
-                    default_sampler: ImageSampler::nearest_descriptor(),
-                }),
+                .disable::<WinitPlugin>(),
         )
-        .add_plugin(LogDiagnosticsPlugin::default())
-        .add_plugin(FrameTimeDiagnosticsPlugin::default())
+        .add_plugin(ScheduleRunnerPlugin);
+    } else {
+        app.insert_resource(CameraZoom::default())
+            .insert_resource(CameraTarget::default())
+            .insert_resource(config.camera.clone().unwrap_or_default())
+            .insert_resource(config.minimap.clone().unwrap_or_default())
+            .insert_resource(CameraFollow::default())
+            .add_plugins(DefaultPlugins.set(window_plugin(&cli)).set(ImagePlugin {
+                default_sampler: ImageSampler::nearest_descriptor(),
+            }))
+            .add_plugin(LogDiagnosticsPlugin::default())
+            .add_plugin(FrameTimeDiagnosticsPlugin::default())
+            .add_plugin(MinimapPlugin::<S>)
+            .add_plugin(HelpPlugin)
+            .add_plugin(ClusterOverlayPlugin::<S>)
+            .add_startup_system(setup)
+            .add_system(camera_framing.before("camera"))
+            .add_system(camera_bookmarks.before("camera"))
+            .add_system(cursor_movement.label("cursor"))
+            .add_system(camera_movement.label("camera").after("cursor"))
+            .add_system(follow_controls.label("follow_controls").after("camera"))
+            .add_system(
+                camera_follow
+                    .label("camera_follow")
+                    .after("follow_controls")
+                    .after("clusters")
+                    .after("minimap"),
+            )
+            .add_system(
+                camera_smoothing
+                    .label("camera_smoothing")
+                    .after("camera_follow"),
+            );
+    }
+
+    let mut recording = config.recording.clone().unwrap_or_default();
+    cli.apply_recording(&mut recording);
+
+    // Without a renderer both capture on the CPU
+    app.insert_resource(config.screenshots.clone().unwrap_or_default())
+        .insert_resource(recording)
+        .add_plugin(CapturePlugin::<S>)
+        .add_plugin(RecordingPlugin::<S>);
+    if let Some(ticks) = cli.record {
+        app.insert_resource(ScheduledRecording::new(ticks));
+    }
+
+    add_optional_plugins::<S>(&mut app, &cli, &config, rules_seed, cli.metrics.clone());
+
+    if let Some(steps) = cli.steps {
+        app.insert_resource(StepLimit::new(steps, cli.output.clone()))
+            .add_system(
+                exit_after_steps::<S>
+                    .run_if_resource_exists::<Chunks>()
+                    .label("exit")
+                    .after("tick"),
+            );
+    }
+
+    app
         //.add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
         .add_plugin(InputManagerPlugin::<Action>::default())
-        .add_startup_system(setup)
+        .add_plugin(SimulationPlugin::<S>)
+        .add_plugin(ConfigPlugin {
+            path: cli.config.clone(),
+        })
         .add_startup_system(action_setup)
-        .add_system(camera_movement)
-        .add_system(controls)
-        .add_system(configure::<SIZE>)
+        .add_system(quicksave::<S>)
+        .add_system(quickload::<S>)
+        .add_system(replay_controls)
+        .add_system(intervention_controls::<S>.before("interventions"))
         .add_system(
-            update_rules::<SIZE>
+            play_replay
                 .run_if_resource_exists::<Chunks>()
-                .label("update"),
+                .before("interventions"),
         )
-        .add_system(update_edge.run_if_resource_exists::<Chunks>().label("edge"))
+        .add_system(record_interventions.after("interventions"))
         .add_system(
-            apply_velocity
+            record_step::<S>
                 .run_if_resource_exists::<Chunks>()
-                .label("apply_velocity")
-                .after("update")
-                .after("edge"),
+                .after("tick"),
         )
         .add_system(
-            sort_into_chunks
+            advance_replay
                 .run_if_resource_exists::<Chunks>()
-                .after("apply_velocity"),
+                .after("tick"),
         )
         .run();
 }
 
+// Plugins chosen on the command line, the same for the main world and every universe
+fn add_optional_plugins<const S: usize>(
+    app: &mut App,
+    cli: &Cli,
+    config: &ConfigFile,
+    rules_seed: u64,
+    metrics: Option<PathBuf>,
+) {
+    if cli.ecology {
+        app.insert_resource(config.ecology.clone().unwrap_or_default())
+            .add_plugin(EcologyPlugin::<S>);
+    }
+
+    if cli.genomes {
+        app.insert_resource(config.genome.clone().unwrap_or_default())
+            .add_plugin(GenomePlugin::<S>);
+    }
+
+    if let Some(kind) = cli.conversion {
+        let conversion_settings = config.conversion.clone().unwrap_or_default();
+        app.insert_resource(Conversions::<S>::new(
+            kind,
+            rules_seed,
+            &conversion_settings,
+        ))
+        .insert_resource(conversion_settings)
+        .add_plugin(ConversionPlugin::<S>);
+    }
+
+    if let Some(path) = metrics {
+        app.add_plugin(MetricsPlugin::<S> {
+            path,
+            interval: cli.metrics_interval,
+        });
+    }
+}
+
+fn window_plugin(cli: &Cli) -> WindowPlugin {
+    WindowPlugin {
+        window: WindowDescriptor {
+            title: "Particle Life".into(),
+            width: cli.window_width,
+            height: cli.window_height,
+            mode: if cli.fullscreen {
+                WindowMode::BorderlessFullscreen
+            } else {
+                WindowMode::Windowed
+            },
+            resizable: true,
+            cursor_visible: true,
+            cursor_grab_mode: CursorGrabMode::None,
+            ..default()
+        },
+        exit_on_all_closed: true,
+        ..default()
+    }
+}
+
 fn setup(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
@@ -119,8 +525,9 @@ fn setup(
                 },
                 ..default()
             },
+            // Clears with `ClearColor`, the configured background
             camera_2d: Camera2d {
-                clear_color: ClearColorConfig::Custom(Color::DARK_GRAY),
+                clear_color: ClearColorConfig::Default,
                 ..default()
             },
             camera: Camera {
@@ -132,5 +539,6 @@ fn setup(
         .insert(BloomSettings {
             threshold: 0.5,
             ..default()
-        });
+        })
+        .insert(MainCamera);
 }
@@ -0,0 +1,188 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use iyes_loopless::prelude::IntoConditionalSystem;
+
+use crate::{
+    clusters::Clusters,
+    entity::particle::{GroupId, ParticleMarker, Position, Velocity},
+    resources::{chunks::Chunks, settings::Settings, tick::Tick},
+};
+
+// Writes one CSV row of simulation metrics every `interval` ticks
+pub struct MetricsPlugin<const S: usize> {
+    pub path: PathBuf,
+    pub interval: u64,
+}
+
+impl<const S: usize> Plugin for MetricsPlugin<S> {
+    fn build(&self, app: &mut App) {
+        match MetricsRecorder::create::<S>(&self.path, self.interval) {
+            Ok(recorder) => {
+                info!("Writing metrics to {}", self.path.display());
+                app.insert_resource(recorder).add_system(
+                    record_metrics::<S>
+                        .run_if_resource_exists::<Chunks>()
+                        .after("clusters"),
+                );
+            }
+            Err(err) => error!(
+                "Failed to create metrics file {}: {}",
+                self.path.display(),
+                err
+            ),
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct MetricsRecorder {
+    writer: BufWriter<File>,
+    interval: u64,
+}
+
+impl MetricsRecorder {
+    fn create<const S: usize>(path: &Path, interval: u64) -> io::Result<Self> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut header = vec![
+            "tick".to_string(),
+            "kinetic_energy".into(),
+            "mean_speed".into(),
+        ];
+        for group in 0..S {
+            header.push(format!("group{}_center_x", group));
+            header.push(format!("group{}_center_y", group));
+            header.push(format!("group{}_dispersion", group));
+            header.push(format!("group{}_population", group));
+        }
+        header.push("mean_neighbours".into());
+        header.push("clusters".into());
+        writeln!(writer, "{}", header.join(","))?;
+
+        Ok(Self {
+            writer,
+            interval: interval.max(1),
+        })
+    }
+}
+
+pub struct Metrics<const S: usize> {
+    pub kinetic_energy: f32,
+    pub mean_speed: f32,
+    // Center of mass of every group, and the root mean square distance of its particles to it
+    pub centers: [Vec2; S],
+    pub dispersions: [f32; S],
+    pub populations: [usize; S],
+    // Particles within interaction distance of a particle, averaged over all particles
+    pub mean_neighbours: f32,
+    pub clusters: usize,
+}
+
+impl<const S: usize> Metrics<S> {
+    pub fn measure(
+        settings: &Settings,
+        chunks: &Chunks,
+        particles: &[(&Position, &Velocity, &GroupId)],
+        clusters: usize,
+    ) -> Self {
+        let mut kinetic_energy = 0.;
+        let mut speed_sum = 0.;
+        let mut counts = [0usize; S];
+        let mut sums = [Vec2::ZERO; S];
+        for (pos, vel, id) in particles {
+            let speed = vel.0.length();
+            kinetic_energy += 0.5 * settings.mass * speed * speed;
+            speed_sum += speed;
+            counts[id.0] += 1;
+            sums[id.0] += pos.0;
+        }
+
+        let mut centers = [Vec2::ZERO; S];
+        for group in 0..S {
+            if counts[group] > 0 {
+                centers[group] = sums[group] / counts[group] as f32;
+            }
+        }
+
+        let mut squared_distances = [0.; S];
+        for (pos, _, id) in particles {
+            squared_distances[id.0] += pos.0.distance_squared(centers[id.0]);
+        }
+        let mut dispersions = [0.; S];
+        for group in 0..S {
+            if counts[group] > 0 {
+                dispersions[group] = (squared_distances[group] / counts[group] as f32).sqrt();
+            }
+        }
+
+        let total: usize = counts.iter().sum();
+        let neighbours: usize = chunks
+            .chunks
+            .iter()
+            .flat_map(|chunk| chunk.particles.iter())
+            .map(|(entity, pos, _)| {
+                chunks
+                    .particles_within(pos.0, settings.max_dist)
+                    .filter(|(other, _, _)| other != entity)
+                    .count()
+            })
+            .sum();
+
+        Self {
+            kinetic_energy,
+            mean_speed: speed_sum / total.max(1) as f32,
+            centers,
+            dispersions,
+            populations: counts,
+            mean_neighbours: neighbours as f32 / total.max(1) as f32,
+            clusters,
+        }
+    }
+
+    fn write_row(&self, writer: &mut impl Write, tick: u64) -> io::Result<()> {
+        let mut row = vec![
+            tick.to_string(),
+            self.kinetic_energy.to_string(),
+            self.mean_speed.to_string(),
+        ];
+        for group in 0..S {
+            row.push(self.centers[group].x.to_string());
+            row.push(self.centers[group].y.to_string());
+            row.push(self.dispersions[group].to_string());
+            row.push(self.populations[group].to_string());
+        }
+        row.push(self.mean_neighbours.to_string());
+        row.push(self.clusters.to_string());
+        writeln!(writer, "{}", row.join(","))?;
+
+        // Windowed runs may end without dropping the writer, so never leave rows in the buffer
+        writer.flush()
+    }
+}
+
+fn record_metrics<const S: usize>(
+    settings: Res<Settings>,
+    chunks: Res<Chunks>,
+    tick: Res<Tick>,
+    clusters: Res<Clusters>,
+    mut recorder: ResMut<MetricsRecorder>,
+    particle_query: Query<(&Position, &Velocity, &GroupId), With<ParticleMarker>>,
+) {
+    if !tick.0.is_multiple_of(recorder.interval) {
+        return;
+    }
+
+    let particles: Vec<_> = particle_query.iter().collect();
+    let metrics = Metrics::<S>::measure(&settings, &chunks, &particles, clusters.clusters.len());
+    if let Err(err) = metrics.write_row(&mut recorder.writer, tick.0) {
+        error!("Failed to write metrics: {}", err);
+    }
+}
@@ -0,0 +1,297 @@
+use bevy::{
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+    ui::FocusPolicy,
+};
+use leafwing_input_manager::prelude::ActionState;
+use serde::Deserialize;
+
+use crate::{
+    camera::{CameraFollow, CameraTarget, MainCamera, MousePosition},
+    capture::rasterize,
+    entity::particle::{GroupId, ParticleMarker, Position},
+    resources::{actions::Action, rules::Rules, world::WorldSize},
+};
+
+// Distance between the minimap and the window corner, in pixels
+const MINIMAP_MARGIN: f32 = 10.;
+
+#[derive(Resource, Deserialize, Clone)]
+#[serde(default)]
+pub struct MinimapSettings {
+    // Width in pixels, the height follows the aspect ratio of the world
+    pub width: u32,
+    // Frames between two redraws of the particle density
+    pub interval: u32,
+    pub visible: bool,
+}
+
+impl Default for MinimapSettings {
+    fn default() -> Self {
+        Self {
+            width: 240,
+            interval: 4,
+            visible: true,
+        }
+    }
+}
+
+impl MinimapSettings {
+    fn size(&self, world: &WorldSize) -> UVec2 {
+        let height = self.width as usize * world.height / world.width.max(1);
+        UVec2::new(self.width.max(1), (height as u32).max(1))
+    }
+}
+
+#[derive(Resource)]
+struct MinimapImage(Handle<Image>);
+
+#[derive(Component)]
+struct Minimap;
+
+#[derive(Component)]
+struct MinimapViewport;
+
+pub struct MinimapPlugin<const S: usize>;
+
+impl<const S: usize> Plugin for MinimapPlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MinimapSettings>()
+            .add_startup_system(setup_minimap)
+            .add_system(minimap_controls)
+            .add_system(resize_minimap.before("draw_minimap"))
+            .add_system(draw_minimap::<S>.label("draw_minimap"))
+            .add_system(update_minimap_viewport.after("camera_smoothing"))
+            .add_system(minimap_navigation.label("minimap").after("follow_controls"));
+    }
+}
+
+fn setup_minimap(
+    mut commands: Commands,
+    settings: Res<MinimapSettings>,
+    world: Res<WorldSize>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let size = settings.size(&world);
+    let image = images.add(Image::new_fill(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 255],
+        TextureFormat::Rgba8UnormSrgb,
+    ));
+    commands.insert_resource(MinimapImage(image.clone()));
+
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    right: Val::Px(MINIMAP_MARGIN),
+                    bottom: Val::Px(MINIMAP_MARGIN),
+                    ..default()
+                },
+                size: Size::new(Val::Px(size.x as f32), Val::Px(size.y as f32)),
+                overflow: Overflow::Hidden,
+                ..default()
+            },
+            ..default()
+        })
+        .insert((Minimap, Interaction::default()))
+        .with_children(|parent| {
+            parent.spawn(ImageBundle {
+                style: Style {
+                    size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                    ..default()
+                },
+                image: UiImage(image),
+                focus_policy: FocusPolicy::Pass,
+                ..default()
+            });
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        ..default()
+                    },
+                    background_color: Color::rgba(1., 1., 1., 0.2).into(),
+                    focus_policy: FocusPolicy::Pass,
+                    ..default()
+                })
+                .insert(MinimapViewport);
+        });
+}
+
+// A hot-reload may change the world size or the minimap width, keep the aspect ratio of the world
+fn resize_minimap(
+    settings: Res<MinimapSettings>,
+    world: Res<WorldSize>,
+    minimap_image: Res<MinimapImage>,
+    mut images: ResMut<Assets<Image>>,
+    mut minimap_query: Query<&mut Style, With<Minimap>>,
+) {
+    if !settings.is_changed() && !world.is_changed() {
+        return;
+    }
+
+    let size = settings.size(&world);
+    let Some(image) = images.get_mut(&minimap_image.0) else {
+        return;
+    };
+    if image.size() == size.as_vec2() {
+        return;
+    }
+
+    image.resize(Extent3d {
+        width: size.x,
+        height: size.y,
+        depth_or_array_layers: 1,
+    });
+    for mut style in minimap_query.iter_mut() {
+        style.size = Size::new(Val::Px(size.x as f32), Val::Px(size.y as f32));
+    }
+}
+
+fn minimap_controls(
+    mut settings: ResMut<MinimapSettings>,
+    action_query: Query<&ActionState<Action>>,
+    mut minimap_query: Query<&mut Visibility, With<Minimap>>,
+) {
+    let action_state = action_query.single();
+
+    if action_state.just_pressed(Action::ToggleMinimap) {
+        settings.visible = !settings.visible;
+    }
+
+    for mut visibility in minimap_query.iter_mut() {
+        if visibility.is_visible != settings.visible {
+            visibility.is_visible = settings.visible;
+        }
+    }
+}
+
+fn draw_minimap<const S: usize>(
+    mut frames: Local<u32>,
+    rules: Res<Rules<S>>,
+    world: Res<WorldSize>,
+    settings: Res<MinimapSettings>,
+    minimap_image: Res<MinimapImage>,
+    mut images: ResMut<Assets<Image>>,
+    particle_query: Query<(&Position, &GroupId), With<ParticleMarker>>,
+) {
+    if !settings.visible {
+        return;
+    }
+
+    // A resized image is blank until it is drawn again
+    *frames += 1;
+    if *frames < settings.interval && !world.is_changed() && !settings.is_changed() {
+        return;
+    }
+    *frames = 0;
+
+    let Some(image) = images.get_mut(&minimap_image.0) else {
+        return;
+    };
+
+    // Fit the whole world into the image
+    let size = image.size();
+    let world_size = world.extent();
+    let world_per_pixel = (world_size / size).max_element();
+
+    let density = rasterize(
+        particle_query
+            .iter()
+            .map(|(pos, id)| (pos.0, rules.colors[id.0])),
+        -world_size / 2.,
+        world_per_pixel,
+        size.x as u32,
+        size.y as u32,
+        Color::rgb(0.1, 0.1, 0.1),
+    );
+    image.data = density.into_raw();
+}
+
+// Rectangle of the world currently visible in the main camera, relative to the world size
+fn visible_fraction(window: &Window, camera_transform: &Transform, world: &WorldSize) -> Rect {
+    let world_size = world.extent();
+    let center = camera_transform.translation.truncate() / world_size + 0.5;
+    let extent =
+        Vec2::new(window.width(), window.height()) * camera_transform.scale.truncate() / world_size;
+
+    Rect::from_center_size(center, extent)
+}
+
+fn update_minimap_viewport(
+    windows: Res<Windows>,
+    settings: Res<MinimapSettings>,
+    world: Res<WorldSize>,
+    camera_query: Query<&Transform, With<MainCamera>>,
+    mut viewport_query: Query<&mut Style, With<MinimapViewport>>,
+) {
+    let Some(window) = windows.get_primary() else {
+        return;
+    };
+    let visible = visible_fraction(window, camera_query.single(), &world);
+    let size = settings.size(&world).as_vec2();
+
+    for mut style in viewport_query.iter_mut() {
+        // UI positions are measured from the top, world positions from the bottom
+        style.position = UiRect {
+            left: Val::Px(visible.min.x * size.x),
+            top: Val::Px((1. - visible.max.y) * size.y),
+            ..default()
+        };
+        style.size = Size::new(
+            Val::Px(visible.width() * size.x),
+            Val::Px(visible.height() * size.y),
+        );
+    }
+}
+
+fn minimap_navigation(
+    windows: Res<Windows>,
+    world: Res<WorldSize>,
+    mouse_position: Res<MousePosition>,
+    mut follow: ResMut<CameraFollow>,
+    mut camera_target: ResMut<CameraTarget>,
+    action_query: Query<&ActionState<Action>>,
+    minimap_query: Query<(&Interaction, &Node, &GlobalTransform), With<Minimap>>,
+) {
+    let action_state = action_query.single();
+    let Some(window) = windows.get_primary() else {
+        return;
+    };
+
+    for (interaction, node, transform) in minimap_query.iter() {
+        if *interaction == Interaction::None {
+            continue;
+        }
+
+        // A click on the minimap is not meant for the world underneath it
+        if action_state.just_released(Action::CameraPan) {
+            follow.target = None;
+        }
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+
+        // UI coordinates start at the top left of the window, cursor coordinates at the bottom left
+        let cursor = Vec2::new(
+            mouse_position.screen.x,
+            window.height() - mouse_position.screen.y,
+        );
+        let min = transform.translation().truncate() - node.size() / 2.;
+        let fraction = ((cursor - min) / node.size()).clamp(Vec2::ZERO, Vec2::ONE);
+
+        let world_size = world.extent();
+        camera_target.translation = Vec2::new(
+            (fraction.x - 0.5) * world_size.x,
+            (0.5 - fraction.y) * world_size.y,
+        );
+        follow.target = None;
+    }
+}
@@ -1,20 +1,485 @@
-use bevy::prelude::{Query, Res, Transform, With};
+use bevy::prelude::{Entity, Query, Res, Transform, Vec2, With};
+use rayon::prelude::*;
 
 use crate::{
-    entity::particle::{ParticleMarker, Position, Velocity},
-    resources::settings::Settings,
+    entity::particle::{Force, GroupId, ParticleMarker, Position, PreviousForce, Velocity},
+    genome::Genome,
+    resources::{
+        chunks::Chunks,
+        rules::Rules,
+        settings::{Integrator, Settings},
+    },
+    simulation::particle_force,
 };
 
-pub fn apply_velocity(
+type MovingParticleQuery<'w, 's, const S: usize> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static mut Velocity,
+        &'static mut Position,
+        &'static mut Transform,
+        &'static Force,
+        &'static mut PreviousForce,
+        &'static GroupId,
+        Option<&'static Genome<S>>,
+    ),
+    With<ParticleMarker>,
+>;
+
+pub fn apply_velocity<const S: usize>(
+    rules: Res<Rules<S>>,
     settings: Res<Settings>,
-    mut particle_query: Query<(&mut Velocity, &mut Position, &mut Transform), With<ParticleMarker>>,
+    chunks: Res<Chunks>,
+    mut particle_query: MovingParticleQuery<S>,
 ) {
-    particle_query.par_for_each_mut(64, |(mut vel, mut pos, mut trans)| {
-        vel.0 = vel.0.clamp_length(0.0, settings.max_velocity);
-		pos.0 += vel.0;
-        vel.0 *= settings.drag_coef;
+    let mut motions = Vec::new();
+    let mut particles = Vec::new();
+    for (entity, vel, pos, _, force, previous, id, genome) in particle_query.iter() {
+        motions.push(Motion {
+            pos: pos.0,
+            vel: vel.0,
+            force: force.0,
+            previous: previous.0,
+        });
+        particles.push((entity, id.0, genome));
+    }
+
+    // Forces between the particles with all of them at `positions`, in chunks laid out like the
+    // ones of the world
+    let interactions = |positions: &[Vec2]| -> Vec<Vec2> {
+        if !settings.forces.interactions {
+            return vec![Vec2::ZERO; positions.len()];
+        }
+
+        let mut moved = Chunks::new(chunks.width, chunks.height, chunks.size, chunks.origin);
+        for ((entity, id, _), pos) in particles.iter().zip(positions) {
+            moved.insert_particle(*entity, Position(*pos), GroupId(*id));
+        }
+        if settings.deterministic {
+            moved.sort();
+        }
+
+        particles
+            .par_iter()
+            .zip(positions)
+            .map(|((entity, id, genome), pos)| {
+                particle_force(&rules, &settings, &moved, *entity, *pos, *id, *genome)
+            })
+            .collect()
+    };
+    integrate(&settings, &mut motions, interactions);
+
+    // Nothing was spawned or despawned in between, so the query runs in the same order
+    for ((_, mut vel, mut pos, mut trans, force, mut previous, _, _), motion) in
+        particle_query.iter_mut().zip(motions)
+    {
+        pos.0 = motion.pos;
+        vel.0 = motion.vel;
+        previous.0 = Some(force.0);
 
         trans.translation.x = pos.0.x;
         trans.translation.y = pos.0.y;
-    });
+    }
+}
+
+// A particle as the integrators see it. `force` acts on it where it is now, `previous` acted on
+// it the tick before
+#[derive(Clone, Copy, Debug)]
+pub struct Motion {
+    pub pos: Vec2,
+    pub vel: Vec2,
+    pub force: Vec2,
+    pub previous: Option<Vec2>,
+}
+
+// Moves every particle by one tick. `interactions` gives the forces the particles exert on each
+// other with all of them at the given positions. Integrators looking at the forces part way
+// through a tick take the interactions from it and keep every other force as it was at the
+// start. Every force is capped at `max_force` before it accelerates a particle
+pub fn integrate(
+    settings: &Settings,
+    particles: &mut [Motion],
+    interactions: impl Fn(&[Vec2]) -> Vec<Vec2>,
+) {
+    let mass = settings.mass.max(f32::EPSILON);
+    let acc = |force: Vec2| force.clamp_length_max(settings.max_force) / mass;
+    let clamp = |vel: Vec2| vel.clamp_length(0.0, settings.max_velocity);
+
+    // Accelerations with the particles at `positions` instead of where the tick started
+    let forces: Vec<Vec2> = particles.iter().map(|particle| particle.force).collect();
+    let acc_at = |at_start: &[Vec2], positions: &[Vec2]| -> Vec<Vec2> {
+        forces
+            .iter()
+            .zip(at_start)
+            .zip(interactions(positions))
+            .map(|((force, start), moved)| acc(*force - *start + moved))
+            .collect()
+    };
+
+    match settings.integrator {
+        Integrator::Euler => {
+            for particle in particles.iter_mut() {
+                particle.vel = clamp(particle.vel + acc(particle.force));
+                particle.pos += particle.vel;
+            }
+        }
+        Integrator::VelocityVerlet => {
+            for particle in particles.iter_mut() {
+                // The velocity of the last tick needed the force at its end, which is known now
+                if let Some(previous) = particle.previous {
+                    particle.vel += (acc(previous) + acc(particle.force)) / 2.;
+                }
+                particle.vel = clamp(particle.vel);
+                particle.pos += particle.vel + acc(particle.force) / 2.;
+            }
+        }
+        Integrator::Leapfrog => {
+            let at_start = interactions(&positions(particles));
+            // Half a kick, a drift at the velocity half way through the tick and the other half
+            // of the kick with the forces at the end of it
+            for particle in particles.iter_mut() {
+                particle.vel = clamp(particle.vel + acc(particle.force) / 2.);
+                particle.pos += particle.vel;
+            }
+            let at_end = acc_at(&at_start, &positions(particles));
+            for (particle, acc) in particles.iter_mut().zip(at_end) {
+                particle.vel = clamp(particle.vel + acc / 2.);
+            }
+        }
+        Integrator::Rk4 => {
+            let at_start = interactions(&positions(particles));
+            let (x1, v1): (Vec<Vec2>, Vec<Vec2>) = particles
+                .iter()
+                .map(|particle| (particle.pos, particle.vel))
+                .unzip();
+            let a1: Vec<Vec2> = particles
+                .iter()
+                .map(|particle| acc(particle.force))
+                .collect();
+
+            // Every stage moves all particles from the start by a part of the slopes before it
+            let stage = |vel: &[Vec2], acc: &[Vec2], part: f32| -> (Vec<Vec2>, Vec<Vec2>) {
+                let x = x1.iter().zip(vel).map(|(x, v)| *x + *v * part).collect();
+                let v = v1.iter().zip(acc).map(|(v, a)| *v + *a * part).collect();
+                (x, v)
+            };
+            let (x2, v2) = stage(&v1, &a1, 0.5);
+            let a2 = acc_at(&at_start, &x2);
+            let (x3, v3) = stage(&v2, &a2, 0.5);
+            let a3 = acc_at(&at_start, &x3);
+            let (x4, v4) = stage(&v3, &a3, 1.);
+            let a4 = acc_at(&at_start, &x4);
+
+            for (index, particle) in particles.iter_mut().enumerate() {
+                particle.pos += (v1[index] + 2. * v2[index] + 2. * v3[index] + v4[index]) / 6.;
+                particle.vel = clamp(
+                    particle.vel + (a1[index] + 2. * a2[index] + 2. * a3[index] + a4[index]) / 6.,
+                );
+            }
+        }
+    }
+
+    for particle in particles.iter_mut() {
+        particle.vel *= settings.drag_coef;
+    }
+}
+
+fn positions(particles: &[Motion]) -> Vec<Vec2> {
+    particles.iter().map(|particle| particle.pos).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::World;
+    use clap::ValueEnum;
+
+    use super::*;
+    use crate::{
+        resources::{settings::EdgeMode, world::WorldSize},
+        universe::Universe,
+    };
+
+    fn settings(integrator: Integrator) -> Settings {
+        Settings {
+            integrator,
+            drag_coef: 1.0,
+            max_velocity: f32::MAX,
+            max_force: f32::MAX,
+            ..Settings::default()
+        }
+    }
+
+    fn no_interactions(positions: &[Vec2]) -> Vec<Vec2> {
+        vec![Vec2::ZERO; positions.len()]
+    }
+
+    // Position and velocity after `ticks` ticks of a constant force from rest
+    fn constant_force(settings: &Settings, force: Vec2, ticks: usize) -> (Vec2, Vec2) {
+        let mut particle = [Motion {
+            pos: Vec2::ZERO,
+            vel: Vec2::ZERO,
+            force,
+            previous: None,
+        }];
+        for _ in 0..ticks {
+            integrate(settings, &mut particle, no_interactions);
+            particle[0].previous = Some(force);
+        }
+        (particle[0].pos, particle[0].vel)
+    }
+
+    #[test]
+    fn higher_order_integrators_follow_a_constant_force_exactly() {
+        let force = Vec2::new(1., -2.);
+        let exact = (force * 10. * 10. / 2., force * 10.);
+
+        let (pos, vel) = constant_force(&settings(Integrator::Euler), force, 10);
+        // Each velocity moves the particle a whole tick after the force changed it
+        assert_eq!((pos, vel), (force * 10. * 11. / 2., force * 10.));
+
+        let (pos, vel) = constant_force(&settings(Integrator::VelocityVerlet), force, 10);
+        // The velocity waits for the force at the end of the last tick
+        assert_eq!((pos, vel), (exact.0, force * 9.));
+
+        for integrator in [Integrator::Leapfrog, Integrator::Rk4] {
+            let motion = constant_force(&settings(integrator), force, 10);
+            assert_eq!(motion, exact, "{:?}", integrator);
+        }
+    }
+
+    #[test]
+    fn rk4_moves_all_particles_together() {
+        // Two particles on a spring pulling with 0.005 per unit, so the distance between them
+        // turns one radian every 10 ticks. Moving one while the other stays put would get the
+        // stages wrong
+        let spring = |positions: &[Vec2]| {
+            vec![
+                (positions[1] - positions[0]) * 0.005,
+                (positions[0] - positions[1]) * 0.005,
+            ]
+        };
+        let mut particles = [Vec2::new(-1., 0.), Vec2::new(1., 0.)].map(|pos| Motion {
+            pos,
+            vel: Vec2::ZERO,
+            force: Vec2::ZERO,
+            previous: None,
+        });
+        for _ in 0..100 {
+            let forces = spring(&positions(&particles));
+            for (particle, force) in particles.iter_mut().zip(forces) {
+                particle.force = force;
+            }
+            integrate(&settings(Integrator::Rk4), &mut particles, spring);
+        }
+        assert!((particles[1].pos.x - 10f32.cos()).abs() < 1e-4);
+        assert!((particles[1].vel.x + 10f32.sin() * 0.1).abs() < 1e-4);
+        assert!((particles[0].pos + particles[1].pos).length() < 1e-5);
+    }
+
+    #[test]
+    fn forces_besides_interactions_stay_for_the_whole_tick() {
+        // No interactions at all, only a push that would change if it were looked up again
+        let push = Vec2::new(0., 1.);
+        let mut particle = [Motion {
+            pos: Vec2::ZERO,
+            vel: Vec2::ZERO,
+            force: push,
+            previous: None,
+        }];
+        integrate(&settings(Integrator::Rk4), &mut particle, no_interactions);
+        assert_eq!((particle[0].pos, particle[0].vel), (push / 2., push));
+    }
+
+    #[test]
+    fn limits_mass_and_drag_apply_to_every_integrator() {
+        // The force is capped before the mass slows it down
+        let mut heavy = settings(Integrator::Euler);
+        heavy.max_force = 1.;
+        heavy.mass = 2.;
+        assert_eq!(
+            constant_force(&heavy, Vec2::new(10., 0.), 2).1,
+            Vec2::new(1., 0.)
+        );
+
+        for integrator in Integrator::value_variants() {
+            let mut capped = settings(*integrator);
+            capped.max_force = 1.;
+            capped.max_velocity = 3.;
+            capped.mass = 2.;
+            let (_, vel) = constant_force(&capped, Vec2::new(10., 0.), 20);
+            // Clamping the length rounds a little
+            assert!(vel.abs_diff_eq(Vec2::new(3., 0.), 1e-5), "{:?}", integrator);
+
+            let mut dragged = settings(*integrator);
+            dragged.drag_coef = 0.5;
+            let mut particle = [Motion {
+                pos: Vec2::ZERO,
+                vel: Vec2::new(4., 0.),
+                force: Vec2::ZERO,
+                previous: None,
+            }];
+            integrate(&dragged, &mut particle, no_interactions);
+            assert_eq!(
+                (particle[0].pos, particle[0].vel),
+                (Vec2::new(4., 0.), Vec2::new(2., 0.)),
+                "{:?}",
+                integrator
+            );
+        }
+    }
+
+    // Particles pulling on each other like springs: inside the repulsion range of every group the
+    // force grows with the distance, so with a positive `rep_force` and every pair within
+    // `max_dist` each particle swings around the center of all of them, with no jump in the force
+    // anywhere. Without drag, thermal noise, walls or limits the total energy stays the same, so
+    // any change is integration error
+    fn springs<const S: usize>(amount: usize, integrator: Integrator) -> Universe<S> {
+        let settings = Settings {
+            max_dist: 1000.0,
+            wall_range: 0.0,
+            edge_mode: EdgeMode::BOUNCE,
+            temperature: 0.0,
+            deterministic: true,
+            ..settings(integrator)
+        };
+
+        let mut rules = Rules::<S>::from_seed(1);
+        rules.amount = [amount; S];
+        rules.rep_range = [settings.max_dist; S];
+        rules.rep_force = [1.0; S];
+
+        let world = WorldSize {
+            width: 150,
+            height: 150,
+        };
+        Universe::new(rules, settings, world)
+    }
+
+    // Largest change of the total energy over `ticks` ticks, relative to where it started. The
+    // particles start at half their distance from the center, which keeps them off the walls
+    // while they swing through it
+    fn energy_drift<const S: usize>(mut universe: Universe<S>, ticks: u64) -> f32 {
+        // The first step only spawns the particles
+        universe.step();
+        let mut particle_query = universe
+            .world
+            .query_filtered::<(Entity, &mut Position, &GroupId), With<ParticleMarker>>();
+        let mut chunks = Chunks::covering(&universe.size(), universe.settings().max_dist);
+        for (entity, mut pos, id) in particle_query.iter_mut(&mut universe.world) {
+            pos.0 /= 2.;
+            chunks.insert_particle(entity, *pos, *id);
+        }
+        chunks.sort();
+        universe.world.insert_resource(chunks);
+
+        let initial = total_energy(&mut universe);
+        let mut drift: f32 = 0.;
+        while universe.tick() < ticks {
+            universe.step();
+            let energy = total_energy(&mut universe);
+            drift = drift.max(((energy - initial) / initial.abs().max(f32::EPSILON)).abs());
+        }
+        drift
+    }
+
+    #[test]
+    fn higher_order_integrators_drift_less_than_euler() {
+        let euler = energy_drift(springs::<3>(10, Integrator::Euler), 500);
+        assert!(euler < 1e-3, "Euler drifted by {}", euler);
+
+        for integrator in Integrator::value_variants() {
+            if *integrator == Integrator::Euler {
+                continue;
+            }
+            let drift = energy_drift(springs::<3>(10, *integrator), 500);
+            assert!(
+                drift < euler / 2.,
+                "{:?} drifted by {} against {} for Euler",
+                integrator,
+                drift,
+                euler
+            );
+        }
+    }
+
+    // Position, velocity and group of every particle at the same moment. Velocity Verlet only
+    // finishes a velocity once the force at the end of its tick is known, so the velocity it
+    // keeps is the one of the position before the last move
+    fn particle_states(world: &mut World) -> Vec<(Vec2, Vec2, usize)> {
+        let settings = world.resource::<Settings>().clone();
+        let mut particle_query = world.query_filtered::<
+            (&Position, &Velocity, &PreviousForce, &GroupId),
+            With<ParticleMarker>,
+        >();
+        particle_query
+            .iter(world)
+            .map(|(pos, vel, previous, id)| {
+                let pos = match (settings.integrator, previous.0) {
+                    (Integrator::VelocityVerlet, Some(force)) => {
+                        pos.0 - vel.0 - force / settings.mass / 2.
+                    }
+                    _ => pos.0,
+                };
+                (pos, vel.0, id.0)
+            })
+            .collect()
+    }
+
+    // Kinetic energy of every particle plus the potential energy of every interacting pair
+    fn total_energy<const S: usize>(universe: &mut Universe<S>) -> f32 {
+        let particles = particle_states(&mut universe.world);
+        let settings = universe.settings();
+        let rules = universe.rules();
+
+        let mut energy = 0.;
+        for (index, (pos, vel, group)) in particles.iter().enumerate() {
+            energy += 0.5 * settings.mass * vel.length_squared();
+
+            for (other_index, (other_pos, _, other_group)) in particles.iter().enumerate() {
+                let dist = pos.distance(*other_pos);
+                if other_index != index && dist <= settings.max_dist {
+                    // Every pair is visited from both sides
+                    energy += 0.5
+                        * pair_potential(
+                            rules.attractions[*group][*other_group],
+                            rules.rep_range[*group],
+                            rules.rep_force[*group],
+                            settings,
+                            dist,
+                        );
+                }
+            }
+        }
+        energy
+    }
+
+    // Potential energy of two particles `dist` apart, zero where they stop interacting. The
+    // force between them is the negative slope of it
+    fn pair_potential(
+        attraction: f32,
+        rep_range: f32,
+        rep_force: f32,
+        settings: &Settings,
+        dist: f32,
+    ) -> f32 {
+        let half_dist = settings.max_dist / 2.;
+
+        // Integral of the force modifier from 0 to `r`, following `genome_force`
+        let integral = |r: f32| {
+            let repelling = r.min(rep_range);
+            let mut total = rep_force * repelling * repelling / (2. * rep_range);
+            if r > rep_range {
+                let near = r.min(half_dist);
+                total += attraction * (near * near - rep_range * rep_range) / (2. * half_dist);
+            }
+            if r > half_dist {
+                total += attraction * (r - half_dist).powi(2) / (2. * half_dist);
+            }
+            total
+        };
+
+        settings.g * (integral(dist) - integral(settings.max_dist))
+    }
 }
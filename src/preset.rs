@@ -0,0 +1,43 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    resources::{rules::Rules, settings::Settings},
+    snapshot::{RulesSnapshot, SnapshotError},
+};
+
+// Rules worth keeping, without any particle state, stored as JSON so they can be edited by hand
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Preset {
+    pub seed: u64,
+    pub rules: RulesSnapshot,
+    #[serde(default)]
+    pub settings: Option<Settings>,
+}
+
+impl Preset {
+    pub fn new<const S: usize>(rules: &Rules<S>, settings: Option<&Settings>) -> Self {
+        Self {
+            seed: rules.seed,
+            rules: RulesSnapshot::from(rules),
+            settings: settings.cloned(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+        let bytes = fs::read(path)?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+};
+
+use bevy::{
+    app::AppExit,
+    prelude::*,
+    render::{camera::RenderTarget, RenderApp},
+};
+use image::RgbaImage;
+use iyes_loopless::prelude::IntoConditionalSystem;
+use leafwing_input_manager::prelude::ActionState;
+use serde::Deserialize;
+
+use crate::{
+    camera::MainCamera,
+    capture::{
+        capture_camera_bundle, capture_image, capture_name, capture_size, rasterize, save_png,
+        write_png, FrameCapture, FrameCaptured, MainCameraQuery, CAPTURE_WARMUP_FRAMES,
+    },
+    entity::particle::{GroupId, ParticleMarker, Position},
+    resources::{actions::Action, rules::Rules, tick::Tick, world::WorldSize},
+};
+
+#[derive(Resource, Deserialize, Clone)]
+#[serde(default)]
+pub struct RecordingSettings {
+    pub directory: PathBuf,
+    // Simulation ticks between two recorded frames
+    pub interval: u64,
+    pub width: u32,
+    pub height: u32,
+    // Frame rate of the encoded video when piping into ffmpeg
+    pub fps: u32,
+    pub ffmpeg: bool,
+}
+
+impl Default for RecordingSettings {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("recordings"),
+            interval: 1,
+            width: 1920,
+            height: 1080,
+            fps: 60,
+            ffmpeg: true,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct Recorder {
+    session: Option<Session>,
+}
+
+impl Recorder {
+    pub fn is_recording(&self) -> bool {
+        self.session.is_some()
+    }
+}
+
+struct Session {
+    sink: FrameSink,
+    interval: u64,
+    width: u32,
+    height: u32,
+    next_frame: u64,
+    last_tick: Option<u64>,
+    warmup: u8,
+    camera: Option<Entity>,
+}
+
+enum FrameSink {
+    Images(PathBuf),
+    Ffmpeg(Child),
+}
+
+impl FrameSink {
+    fn open(settings: &RecordingSettings, width: u32, height: u32, seed: u64) -> Self {
+        let name = capture_name(seed);
+
+        if settings.ffmpeg {
+            if let Err(err) = fs::create_dir_all(&settings.directory) {
+                error!("Failed to create {}: {}", settings.directory.display(), err);
+            }
+
+            let path = settings.directory.join(format!("{}.mp4", name));
+            let ffmpeg = Command::new("ffmpeg")
+                .args(["-y", "-loglevel", "error"])
+                .args(["-f", "rawvideo", "-pixel_format", "rgba"])
+                .args(["-video_size", &format!("{}x{}", width, height)])
+                .args(["-framerate", &settings.fps.to_string()])
+                .args(["-i", "-", "-c:v", "libx264", "-pix_fmt", "yuv420p"])
+                .arg(&path)
+                .stdin(Stdio::piped())
+                .spawn();
+
+            match ffmpeg {
+                Ok(child) => {
+                    info!("Recording to {}", path.display());
+                    return Self::Ffmpeg(child);
+                }
+                Err(err) => warn!(
+                    "Could not start ffmpeg, writing image frames instead: {}",
+                    err
+                ),
+            }
+        }
+
+        let directory = settings.directory.join(name);
+        info!("Recording frames to {}", directory.display());
+        Self::Images(directory)
+    }
+
+    fn write(&mut self, frame: u64, image: RgbaImage, blocking: bool) {
+        match self {
+            Self::Images(directory) => {
+                let path = directory.join(format!("frame_{:06}.png", frame));
+                if blocking {
+                    write_png(&path, &image);
+                } else {
+                    save_png(path, image);
+                }
+            }
+            Self::Ffmpeg(child) => {
+                let Some(stdin) = child.stdin.as_mut() else {
+                    return;
+                };
+                if let Err(err) = stdin.write_all(image.as_raw()) {
+                    error!("Failed to write frame {} to ffmpeg: {}", frame, err);
+                }
+            }
+        }
+    }
+
+    fn close(self) {
+        if let Self::Ffmpeg(mut child) = self {
+            // Closing stdin lets ffmpeg finish encoding
+            drop(child.stdin.take());
+            if let Err(err) = child.wait() {
+                error!("ffmpeg did not exit cleanly: {}", err);
+            }
+        }
+    }
+}
+
+pub struct RecordingPlugin<const S: usize>;
+
+impl<const S: usize> Plugin for RecordingPlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RecordingSettings>()
+            .init_resource::<Recorder>()
+            .add_system(recording_controls::<S>.before("record"))
+            .add_system(
+                scheduled_recording::<S>
+                    .run_if_resource_exists::<ScheduledRecording>()
+                    .after("tick")
+                    .before("record"),
+            )
+            .add_system(write_frames)
+            .add_system(stop_recording_on_exit.after("record").after("exit"));
+
+        if app.get_sub_app(RenderApp).is_ok() {
+            app.add_system(gpu_record.label("record").after("tick"));
+        } else {
+            app.add_system(cpu_record::<S>.label("record").after("tick"));
+        }
+    }
+}
+
+pub fn start_recording(recorder: &mut Recorder, settings: &RecordingSettings, seed: u64) {
+    if recorder.is_recording() {
+        return;
+    }
+
+    let (width, height, _) = capture_size(settings.width.max(1), settings.height.max(1), 1);
+
+    recorder.session = Some(Session {
+        sink: FrameSink::open(settings, width, height, seed),
+        interval: settings.interval.max(1),
+        width,
+        height,
+        next_frame: 0,
+        last_tick: None,
+        warmup: CAPTURE_WARMUP_FRAMES,
+        camera: None,
+    });
+}
+
+pub fn stop_recording(
+    recorder: &mut Recorder,
+    commands: &mut Commands,
+    images: Option<&mut Assets<Image>>,
+    camera_query: &Query<&Camera, With<FrameCapture>>,
+) {
+    let Some(session) = recorder.session.take() else {
+        return;
+    };
+
+    if let Some(camera) = session.camera {
+        if let (
+            Some(images),
+            Ok(Camera {
+                target: RenderTarget::Image(image),
+                ..
+            }),
+        ) = (images, camera_query.get(camera))
+        {
+            images.remove(image);
+        }
+        commands.entity(camera).despawn();
+    }
+
+    info!("Stopped recording after {} frames", session.next_frame);
+    session.sink.close();
+}
+
+fn recording_controls<const S: usize>(
+    mut commands: Commands,
+    rules: Res<Rules<S>>,
+    settings: Res<RecordingSettings>,
+    mut recorder: ResMut<Recorder>,
+    images: Option<ResMut<Assets<Image>>>,
+    action_query: Query<&ActionState<Action>>,
+    camera_query: Query<&Camera, With<FrameCapture>>,
+) {
+    let action_state = action_query.single();
+
+    if action_state.just_pressed(Action::ToggleRecording) {
+        if recorder.is_recording() {
+            stop_recording(
+                &mut recorder,
+                &mut commands,
+                images.map(|images| images.into_inner()),
+                &camera_query,
+            );
+        } else {
+            start_recording(&mut recorder, &settings, rules.seed);
+        }
+    }
+}
+
+// Records a number of ticks from the start of the run without a key press, for runs without a
+// window
+#[derive(Resource)]
+pub struct ScheduledRecording {
+    pub ticks: u64,
+    started_at: Option<u64>,
+}
+
+impl ScheduledRecording {
+    pub fn new(ticks: u64) -> Self {
+        Self {
+            ticks,
+            started_at: None,
+        }
+    }
+}
+
+fn scheduled_recording<const S: usize>(
+    mut commands: Commands,
+    (rules, tick): (Res<Rules<S>>, Res<Tick>),
+    settings: Res<RecordingSettings>,
+    mut scheduled: ResMut<ScheduledRecording>,
+    mut recorder: ResMut<Recorder>,
+    images: Option<ResMut<Assets<Image>>>,
+    camera_query: Query<&Camera, With<FrameCapture>>,
+) {
+    let Some(started_at) = scheduled.started_at else {
+        start_recording(&mut recorder, &settings, rules.seed);
+        scheduled.started_at = Some(tick.0);
+        return;
+    };
+
+    if tick.0 >= started_at + scheduled.ticks {
+        stop_recording(
+            &mut recorder,
+            &mut commands,
+            images.map(|images| images.into_inner()),
+            &camera_query,
+        );
+        commands.remove_resource::<ScheduledRecording>();
+    }
+}
+
+// Lets ffmpeg finish the video when the app exits while recording, e.g. after --steps
+fn stop_recording_on_exit(
+    mut commands: Commands,
+    mut recorder: ResMut<Recorder>,
+    mut exits: EventReader<AppExit>,
+    camera_query: Query<&Camera, With<FrameCapture>>,
+) {
+    if exits.iter().count() > 0 {
+        stop_recording(&mut recorder, &mut commands, None, &camera_query);
+    }
+}
+
+fn write_frames(mut recorder: ResMut<Recorder>, mut frame_events: EventReader<FrameCaptured>) {
+    for event in frame_events.iter() {
+        // Frames still in flight when recording stopped are dropped
+        if let Some(session) = recorder.session.as_mut() {
+            session.sink.write(event.frame, event.image.clone(), false);
+        }
+    }
+}
+
+// Returns the frame index to record this tick, if any
+fn frame_due(session: &mut Session, tick: u64) -> Option<u64> {
+    if session.last_tick == Some(tick) || !tick.is_multiple_of(session.interval) {
+        return None;
+    }
+
+    session.last_tick = Some(tick);
+    session.next_frame += 1;
+    Some(session.next_frame - 1)
+}
+
+fn gpu_record(
+    mut commands: Commands,
+    tick: Res<Tick>,
+    windows: Res<Windows>,
+    mut recorder: ResMut<Recorder>,
+    mut images: ResMut<Assets<Image>>,
+    main_camera_query: MainCameraQuery,
+    mut capture_query: Query<(&mut Transform, &mut FrameCapture), Without<MainCamera>>,
+) {
+    let Some(session) = recorder.session.as_mut() else {
+        return;
+    };
+    let Some(window) = windows.get_primary() else {
+        return;
+    };
+    let (transform, projection, camera_2d, bloom) = main_camera_query.single();
+
+    // Keep the horizontal extent of the window at the recording resolution
+    let mut capture_transform = *transform;
+    capture_transform.scale =
+        transform.scale * window.physical_width() as f32 / session.width as f32;
+
+    let Some(camera) = session.camera else {
+        let image = capture_image(&mut images, session.width, session.height);
+        let mut capture = commands.spawn(capture_camera_bundle(
+            image,
+            capture_transform,
+            projection.far,
+            camera_2d,
+        ));
+        capture.insert(FrameCapture::default());
+        if let Some(bloom) = bloom {
+            capture.insert(bloom.clone());
+        }
+        session.camera = Some(capture.id());
+        return;
+    };
+
+    let Ok((mut camera_transform, mut capture)) = capture_query.get_mut(camera) else {
+        return;
+    };
+    *camera_transform = capture_transform;
+
+    if session.warmup > 0 {
+        session.warmup -= 1;
+        capture.frame = None;
+        return;
+    }
+
+    capture.frame = frame_due(session, tick.0);
+}
+
+fn cpu_record<const S: usize>(
+    tick: Res<Tick>,
+    rules: Res<Rules<S>>,
+    (world, clear_color): (Res<WorldSize>, Res<ClearColor>),
+    mut recorder: ResMut<Recorder>,
+    particle_query: Query<(&Position, &GroupId), With<ParticleMarker>>,
+) {
+    let Some(session) = recorder.session.as_mut() else {
+        return;
+    };
+    let Some(frame) = frame_due(session, tick.0) else {
+        return;
+    };
+
+    // Fit the whole world into the frame
+    let world_size = world.extent();
+    let world_per_pixel =
+        (world_size.x / session.width as f32).max(world_size.y / session.height as f32);
+    let view_size = Vec2::new(session.width as f32, session.height as f32) * world_per_pixel;
+
+    let image = rasterize(
+        particle_query
+            .iter()
+            .map(|(pos, id)| (pos.0, rules.colors[id.0])),
+        -view_size / 2.,
+        world_per_pixel,
+        session.width,
+        session.height,
+        clear_color.0,
+    );
+
+    // Rendering on the CPU holds up the tick anyway, and the run may exit right after
+    session.sink.write(frame, image, true);
+}
@@ -0,0 +1,210 @@
+use std::{collections::VecDeque, fs, path::Path, path::PathBuf};
+
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capture::capture_name,
+    interventions::Intervention,
+    resources::{actions::Action, rules::Rules, settings::Settings, tick::Tick, world::WorldSize},
+    snapshot::{is_json, load_versioned, Snapshot, SnapshotError, SnapshotParticleQuery},
+};
+
+const REPLAY_VERSION: u32 = 7;
+
+#[derive(Resource)]
+pub struct ReplaySettings {
+    pub directory: PathBuf,
+    pub json: bool,
+}
+
+impl Default for ReplaySettings {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("replays"),
+            json: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    pub initial: Snapshot,
+    // Simulation steps recorded after the initial snapshot
+    pub steps: u64,
+    pub events: Vec<ReplayEvent>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReplayEvent {
+    pub step: u64,
+    pub intervention: Intervention,
+}
+
+impl Replay {
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+
+        let bytes = if is_json(path) {
+            serde_json::to_vec(self)?
+        } else {
+            bincode::serialize(self)?
+        };
+        fs::write(path, bytes)?;
+
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+        load_versioned(path, REPLAY_VERSION)
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ReplayRecorder {
+    replay: Option<Replay>,
+    pending_start: bool,
+}
+
+#[derive(Resource, Default)]
+pub struct ReplayPlayer {
+    events: VecDeque<ReplayEvent>,
+    steps: u64,
+    step: u64,
+    playing: bool,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: &Replay) -> Self {
+        let mut events: Vec<ReplayEvent> = replay.events.clone();
+        events.sort_by_key(|event| event.step);
+
+        Self {
+            events: events.into(),
+            steps: replay.steps,
+            step: 0,
+            playing: true,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+}
+
+pub fn replay_controls(
+    mut commands: Commands,
+    settings: Res<Settings>,
+    replay_settings: Res<ReplaySettings>,
+    player: Res<ReplayPlayer>,
+    mut recorder: ResMut<ReplayRecorder>,
+    action_query: Query<&ActionState<Action>>,
+) {
+    let action_state = action_query.single();
+
+    if !action_state.just_pressed(Action::ToggleReplayRecording) || player.is_playing() {
+        return;
+    }
+
+    if let Some(replay) = recorder.replay.take() {
+        let extension = if replay_settings.json {
+            "json"
+        } else {
+            "replay"
+        };
+        let path = replay_settings.directory.join(format!(
+            "{}.{}",
+            capture_name(replay.initial.seed),
+            extension
+        ));
+        match replay.save(&path) {
+            Ok(()) => info!(
+                "Saved replay of {} steps to {}",
+                replay.steps,
+                path.display()
+            ),
+            Err(err) => error!("Failed to save replay to {}: {}", path.display(), err),
+        }
+        return;
+    }
+
+    if !settings.deterministic {
+        info!("Enabling deterministic mode for replay recording");
+        commands.insert_resource(Settings {
+            deterministic: true,
+            ..settings.clone()
+        });
+    }
+    recorder.pending_start = true;
+}
+
+pub fn record_interventions(
+    mut recorder: ResMut<ReplayRecorder>,
+    mut interventions: EventReader<Intervention>,
+) {
+    for intervention in interventions.iter() {
+        if let Some(replay) = recorder.replay.as_mut() {
+            replay.events.push(ReplayEvent {
+                step: replay.steps,
+                intervention: intervention.clone(),
+            });
+        }
+    }
+}
+
+pub fn record_step<const S: usize>(
+    rules: Res<Rules<S>>,
+    settings: Res<Settings>,
+    world: Res<WorldSize>,
+    tick: Res<Tick>,
+    mut recorder: ResMut<ReplayRecorder>,
+    particle_query: SnapshotParticleQuery<S>,
+) {
+    if let Some(replay) = recorder.replay.as_mut() {
+        replay.steps += 1;
+        return;
+    }
+
+    // Start once deterministic mode has been in effect for a whole step, so the chunks are sorted
+    if recorder.pending_start && settings.deterministic {
+        recorder.pending_start = false;
+        recorder.replay = Some(Replay {
+            version: REPLAY_VERSION,
+            initial: Snapshot::capture(&rules, &settings, &world, tick.0, particle_query.iter()),
+            steps: 0,
+            events: Vec::new(),
+        });
+        info!("Recording replay");
+    }
+}
+
+pub fn play_replay(mut player: ResMut<ReplayPlayer>, mut interventions: EventWriter<Intervention>) {
+    if !player.playing {
+        return;
+    }
+
+    while player
+        .events
+        .front()
+        .is_some_and(|event| event.step <= player.step)
+    {
+        let event = player.events.pop_front().unwrap();
+        interventions.send(event.intervention);
+    }
+}
+
+pub fn advance_replay(mut player: ResMut<ReplayPlayer>) {
+    if !player.playing {
+        return;
+    }
+
+    player.step += 1;
+    if player.step >= player.steps {
+        player.playing = false;
+        info!("Replay finished after {} steps", player.steps);
+    }
+}
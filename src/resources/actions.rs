@@ -1,8 +1,41 @@
-use bevy::prelude::{Commands, Component, KeyCode, MouseButton};
+use std::{
+    collections::BTreeMap,
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::{
+    input::gamepad::{GamepadAxisType, GamepadButtonType},
+    log::{info, warn},
+    prelude::{Commands, Component, KeyCode, MouseButton, Res, Resource},
+};
 use leafwing_input_manager::{
+    axislike::SingleAxis,
     prelude::{ActionState, InputMap},
+    user_input::{InputKind, UserInput},
     Actionlike, InputManagerBundle,
 };
+use serde::{Deserialize, Serialize};
+
+pub const BINDINGS_PATH: &str = "bindings.toml";
+
+#[derive(Resource, Deserialize, Clone)]
+#[serde(default)]
+pub struct InputConfig {
+    pub bindings: PathBuf,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            bindings: PathBuf::from(BINDINGS_PATH),
+        }
+    }
+}
+
+// Stick deflection that counts as pressing a direction
+const STICK_THRESHOLD: f32 = 0.5;
 
 #[derive(Actionlike, PartialEq, Eq, Clone, Copy, Hash, Debug, Component)]
 pub enum Action {
@@ -15,13 +48,63 @@ pub enum Action {
     CameraPan,
     CameraZoomIn,
     CameraZoomOut,
+    CameraFitWorld,
+    CameraFitParticles,
+    CameraFollowCluster,
+    CameraStoreBookmark,
+    CameraBookmark1,
+    CameraBookmark2,
+    CameraBookmark3,
+    CameraBookmark4,
+    CameraBookmark5,
+    CameraBookmark6,
+    CameraBookmark7,
+    CameraBookmark8,
+    CameraBookmark9,
     ToggleDebugColliders,
     ToggleDebugPrints,
     ToggleInspector,
     Restart,
+    Screenshot,
+    PosterScreenshot,
+    ToggleRecording,
+    QuickSave,
+    QuickLoad,
+    ToggleReplayRecording,
+    ForceTool,
+    SpawnTool,
+    ToggleMinimap,
+    ToggleHelp,
+    ToggleClusters,
+}
+
+// Bindings read from the config file, replacing the defaults of every action it lists, e.g.
+//   [bindings]
+//   CameraUp = [{ Keyboard = "Up" }, { GamepadButton = "DPadUp" }]
+//   QuickSave = [[{ Modifier = "Control" }, { Keyboard = "S" }]]
+#[derive(Serialize, Deserialize, Default)]
+struct BindingsFile {
+    #[serde(default)]
+    bindings: BTreeMap<String, Vec<Binding>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Binding {
+    Single(InputKind),
+    Chord(Vec<InputKind>),
+}
+
+impl From<Binding> for UserInput {
+    fn from(binding: Binding) -> Self {
+        match binding {
+            Binding::Single(input) => UserInput::Single(input),
+            Binding::Chord(inputs) => UserInput::chord(inputs),
+        }
+    }
 }
 
-pub(crate) fn action_setup(mut commands: Commands) {
+pub fn default_input_map() -> InputMap<Action> {
     let mut input_map = InputMap::default();
     input_map.insert_multiple([
         (KeyCode::W, Action::CameraUp),
@@ -29,16 +112,162 @@ pub(crate) fn action_setup(mut commands: Commands) {
         (KeyCode::A, Action::CameraLeft),
         (KeyCode::D, Action::CameraRight),
         (KeyCode::LShift, Action::CameraFasterSpeed),
-        (KeyCode::R, Action::CameraReset),
+        (KeyCode::Home, Action::CameraReset),
+        (KeyCode::Equals, Action::CameraZoomIn),
+        (KeyCode::NumpadAdd, Action::CameraZoomIn),
+        (KeyCode::Minus, Action::CameraZoomOut),
+        (KeyCode::NumpadSubtract, Action::CameraZoomOut),
+        (KeyCode::F, Action::CameraFitWorld),
+        (KeyCode::G, Action::CameraFitParticles),
+        (KeyCode::LAlt, Action::CameraFollowCluster),
+        (KeyCode::LControl, Action::CameraStoreBookmark),
+        (KeyCode::RControl, Action::CameraStoreBookmark),
+        (KeyCode::Key1, Action::CameraBookmark1),
+        (KeyCode::Key2, Action::CameraBookmark2),
+        (KeyCode::Key3, Action::CameraBookmark3),
+        (KeyCode::Key4, Action::CameraBookmark4),
+        (KeyCode::Key5, Action::CameraBookmark5),
+        (KeyCode::Key6, Action::CameraBookmark6),
+        (KeyCode::Key7, Action::CameraBookmark7),
+        (KeyCode::Key8, Action::CameraBookmark8),
+        (KeyCode::Key9, Action::CameraBookmark9),
         (KeyCode::P, Action::ToggleDebugColliders),
         (KeyCode::I, Action::ToggleInspector),
         (KeyCode::R, Action::Restart),
+        (KeyCode::F12, Action::Screenshot),
+        (KeyCode::F11, Action::PosterScreenshot),
+        (KeyCode::F9, Action::ToggleRecording),
+        (KeyCode::F5, Action::QuickSave),
+        (KeyCode::F8, Action::QuickLoad),
+        (KeyCode::F10, Action::ToggleReplayRecording),
+        (KeyCode::M, Action::ToggleMinimap),
+        (KeyCode::F1, Action::ToggleHelp),
+        (KeyCode::C, Action::ToggleClusters),
+    ]);
+
+    input_map.insert_multiple([
+        (MouseButton::Left, Action::CameraPan),
+        (MouseButton::Right, Action::ForceTool),
+        (MouseButton::Middle, Action::SpawnTool),
+    ]);
+
+    input_map.insert_multiple([
+        (GamepadButtonType::DPadUp, Action::CameraUp),
+        (GamepadButtonType::DPadDown, Action::CameraDown),
+        (GamepadButtonType::DPadLeft, Action::CameraLeft),
+        (GamepadButtonType::DPadRight, Action::CameraRight),
+        (GamepadButtonType::LeftThumb, Action::CameraFasterSpeed),
+        (GamepadButtonType::Select, Action::CameraReset),
+        (GamepadButtonType::RightTrigger2, Action::CameraZoomIn),
+        (GamepadButtonType::LeftTrigger2, Action::CameraZoomOut),
+        (GamepadButtonType::West, Action::CameraFitWorld),
+        (GamepadButtonType::North, Action::CameraFitParticles),
+        (GamepadButtonType::East, Action::ToggleMinimap),
+        (GamepadButtonType::Start, Action::ToggleHelp),
     ]);
 
-    input_map.insert_multiple([(MouseButton::Left, Action::CameraPan)]);
+    input_map.insert_multiple([
+        (
+            SingleAxis::positive_only(GamepadAxisType::LeftStickY, STICK_THRESHOLD),
+            Action::CameraUp,
+        ),
+        (
+            SingleAxis::negative_only(GamepadAxisType::LeftStickY, -STICK_THRESHOLD),
+            Action::CameraDown,
+        ),
+        (
+            SingleAxis::negative_only(GamepadAxisType::LeftStickX, -STICK_THRESHOLD),
+            Action::CameraLeft,
+        ),
+        (
+            SingleAxis::positive_only(GamepadAxisType::LeftStickX, STICK_THRESHOLD),
+            Action::CameraRight,
+        ),
+    ]);
+
+    input_map
+}
+
+// Default bindings, overridden per action by the bindings file if there is one
+pub fn load_input_map(path: &Path) -> InputMap<Action> {
+    let mut input_map = default_input_map();
+
+    let file = match fs::read_to_string(path) {
+        Ok(contents) => toml::from_str::<BindingsFile>(&contents).map_err(|err| err.to_string()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(BindingsFile::default()),
+        Err(err) => Err(err.to_string()),
+    };
+    let file = file.unwrap_or_else(|err| {
+        warn!("Ignoring key bindings in {}: {}", path.display(), err);
+        BindingsFile::default()
+    });
+
+    if !file.bindings.is_empty() {
+        info!("Loaded key bindings from {}", path.display());
+    }
+
+    for (name, bindings) in file.bindings {
+        let Some(action) = Action::variants().find(|action| format!("{:?}", action) == name) else {
+            warn!("Unknown action {} in {}", name, path.display());
+            continue;
+        };
+
+        input_map.clear_action(action);
+        for binding in bindings {
+            input_map.insert(UserInput::from(binding), action);
+        }
+    }
+
+    warn_conflicts(&input_map);
+    input_map
+}
+
+// Warns about inputs bound to more than one action, they would trigger all of them at once
+fn warn_conflicts(input_map: &InputMap<Action>) {
+    let mut actions_by_input: HashMap<&UserInput, Vec<Action>> = HashMap::new();
+    for (inputs, action) in input_map.iter() {
+        for input in inputs.iter() {
+            actions_by_input.entry(input).or_default().push(action);
+        }
+    }
+
+    for (input, actions) in actions_by_input {
+        if actions.len() > 1 {
+            warn!(
+                "{} is bound to several actions: {:?}",
+                describe_input(input),
+                actions
+            );
+        }
+    }
+}
+
+pub fn describe_input(input: &UserInput) -> String {
+    match input {
+        UserInput::Single(input) => describe_input_kind(input),
+        UserInput::Chord(inputs) => inputs
+            .iter()
+            .map(describe_input_kind)
+            .collect::<Vec<_>>()
+            .join("+"),
+        other => format!("{:?}", other),
+    }
+}
+
+fn describe_input_kind(input: &InputKind) -> String {
+    match input {
+        InputKind::Keyboard(key) => format!("{:?}", key),
+        InputKind::Modifier(modifier) => format!("{:?}", modifier),
+        InputKind::Mouse(button) => format!("Mouse {:?}", button),
+        InputKind::GamepadButton(button) => format!("Gamepad {:?}", button),
+        InputKind::SingleAxis(axis) => format!("Gamepad {:?}", axis.axis_type),
+        other => format!("{:?}", other),
+    }
+}
 
+pub(crate) fn action_setup(mut commands: Commands, input_config: Res<InputConfig>) {
     commands.spawn(InputManagerBundle::<Action> {
         action_state: ActionState::default(),
-        input_map,
+        input_map: load_input_map(&input_config.bindings),
     });
 }
@@ -1,30 +1,52 @@
-use bevy::prelude::{Entity, Resource};
+use bevy::prelude::{Entity, Resource, Vec2};
 
-use crate::entity::particle::{GroupId, Position, Velocity};
+use crate::{
+    entity::particle::{GroupId, Position, Velocity},
+    resources::world::WorldSize,
+};
 
 #[derive(Resource)]
 pub struct Chunks {
     pub width: usize,
     pub height: usize,
     pub size: usize,
+    // World position of the lower left corner of the first chunk
+    pub origin: Vec2,
     pub chunks: Vec<Chunk>,
 }
 
 impl Chunks {
+    // Column and row of the chunk at a world position, positions outside the chunks belong to
+    // the closest one
+    fn coordinates(&self, x: f32, y: f32) -> (usize, usize) {
+        let along = |offset: f32, count: usize| {
+            (offset / self.size as f32)
+                .floor()
+                .clamp(0., count.saturating_sub(1) as f32) as usize
+        };
+        (
+            along(x - self.origin.x, self.width),
+            along(y - self.origin.y, self.height),
+        )
+    }
+
+    fn chunk_mut(&mut self, pos: Position) -> &mut Chunk {
+        let (chunk_x, chunk_y) = self.coordinates(pos.0.x, pos.0.y);
+        &mut self.chunks[chunk_x + chunk_y * self.width]
+    }
+
     pub fn get_chunk(&self, x: f32, y: f32) -> &Chunk {
-        let chunk_x = (x / (self.size as f32)).floor() as usize;
-        let chunk_y = (y / (self.size as f32)).floor() as usize;
+        let (chunk_x, chunk_y) = self.coordinates(x, y);
         &self.chunks[chunk_x + chunk_y * self.width]
     }
 
+    // The chunk at a world position and its neighbours, each of them once
     pub fn get_chunks_around(&self, x: f32, y: f32) -> Vec<&Chunk> {
+        let (chunk_x, chunk_y) = self.coordinates(x, y);
         let mut chunks: Vec<&Chunk> = Vec::new();
-        for dx in -1..2 {
-            for dy in -1..2 {
-                chunks.push(self.get_chunk(
-                    x + (dx * self.size as i32) as f32,
-                    y + (dy * self.size as i32) as f32,
-                ));
+        for around_x in chunk_x.saturating_sub(1)..=(chunk_x + 1).min(self.width - 1) {
+            for around_y in chunk_y.saturating_sub(1)..=(chunk_y + 1).min(self.height - 1) {
+                chunks.push(&self.chunks[around_x + around_y * self.width]);
             }
         }
         chunks
@@ -36,15 +58,75 @@ impl Chunks {
         }
     }
 
-    pub fn insert_particle(&mut self, pos: Position, id: GroupId) {
-        let chunk_x = (pos.0.x / (self.size as f32)).floor() as usize;
-        let chunk_y = (pos.0.y / (self.size as f32)).floor() as usize;
-        self.chunks[chunk_x + chunk_y * self.width]
+    pub fn sort(&mut self) {
+        for chunk in &mut self.chunks {
+            chunk
+                .particles
+                .sort_by(|(_, a_pos, a_id), (_, b_pos, b_id)| {
+                    a_pos
+                        .0
+                        .x
+                        .total_cmp(&b_pos.0.x)
+                        .then(a_pos.0.y.total_cmp(&b_pos.0.y))
+                        .then(a_id.0.cmp(&b_id.0))
+                });
+        }
+    }
+
+    pub fn insert_particle(&mut self, entity: Entity, pos: Position, id: GroupId) {
+        self.chunk_mut(pos).particles.push((entity, pos, id));
+    }
+
+    // Changes the group a particle is stored with, `pos` is the position it was inserted at
+    pub fn set_group(&mut self, entity: Entity, pos: Position, id: GroupId) {
+        if let Some(particle) = self
+            .chunk_mut(pos)
             .particles
-            .push((pos, id));
+            .iter_mut()
+            .find(|(other, _, _)| *other == entity)
+        {
+            particle.2 = id;
+        }
+    }
+
+    // Closest particle within `radius` of the given world position
+    pub fn nearest_particle(&self, pos: Vec2, radius: f32) -> Option<(Entity, Position, GroupId)> {
+        self.get_chunks_around(pos.x, pos.y)
+            .into_iter()
+            .flat_map(|chunk| chunk.particles.iter())
+            .map(|particle| (particle, particle.1 .0.distance(pos)))
+            .filter(|(_, dist)| *dist <= radius)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(particle, _)| *particle)
+    }
+
+    // Particles within `radius` of the given position, which may not exceed the chunk size.
+    // Includes the particle at the position itself, if there is one
+    pub fn particles_within(
+        &self,
+        pos: Vec2,
+        radius: f32,
+    ) -> impl Iterator<Item = &(Entity, Position, GroupId)> {
+        self.get_chunks_around(pos.x, pos.y)
+            .into_iter()
+            .flat_map(|chunk| chunk.particles.iter())
+            .filter(move |(_, other_pos, _)| other_pos.0.distance(pos) <= radius)
+    }
+
+    // Chunks of `size` covering the whole world, particles interact with the chunks around them
+    // so `size` should be the interaction distance
+    pub fn covering(world: &WorldSize, size: f32) -> Self {
+        let chunks_x = (world.extent().x / size).ceil();
+        let chunks_y = (world.extent().y / size).ceil();
+        Self::new(
+            chunks_x as usize,
+            chunks_y as usize,
+            size as usize,
+            -world.bounds(),
+        )
     }
 
-    pub fn new(width: usize, height: usize, size: usize) -> Self {
+    pub fn new(width: usize, height: usize, size: usize, origin: Vec2) -> Self {
         let mut chunks: Vec<Chunk> = Vec::new();
         for y in 0..height {
             for x in 0..width {
@@ -56,13 +138,14 @@ impl Chunks {
             width,
             height,
             size,
+            origin,
             chunks,
         }
     }
 }
 
 pub struct Chunk {
-    pub particles: Vec<(Position, GroupId)>,
+    pub particles: Vec<(Entity, Position, GroupId)>,
 }
 
 impl Chunk {
@@ -2,3 +2,5 @@ pub mod actions;
 pub mod chunks;
 pub mod rules;
 pub mod settings;
+pub mod tick;
+pub mod world;
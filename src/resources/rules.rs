@@ -1,8 +1,9 @@
 use bevy::prelude::{Color, Resource};
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
-#[derive(Resource)]
+#[derive(Resource, Clone)]
 pub struct Rules<const S: usize> {
+    pub seed: u64,
     pub amount: [usize; S],
     pub attractions: [[f32; S]; S],
     pub colors: [Color; S],
@@ -20,6 +21,7 @@ pub struct Rule<const S: usize> {
 
 impl<const S: usize> Rules<S> {
     pub fn new(
+        seed: u64,
         amount: [usize; S],
         attractions: [[f32; S]; S],
         colors: [Color; S],
@@ -27,6 +29,7 @@ impl<const S: usize> Rules<S> {
         rep_force: [f32; S],
     ) -> Self {
         return Self {
+            seed,
             amount,
             attractions,
             colors,
@@ -43,8 +46,20 @@ impl<const S: usize> Rules<S> {
     ];
     const default_colors: [Color; 4] = [Color::RED, Color::GREEN, Color::BLUE, Color::WHITE];
 
+    // The default palette, continued with hues a golden angle apart for larger group counts
+    fn default_color(group: usize) -> Color {
+        match Self::default_colors_hsla.get(group) {
+            Some(color) => *color,
+            None => Color::hsla((349.0 + group as f32 * 137.5) % 360.0, 1.0, 0.6, 1.0),
+        }
+    }
+
     pub fn random() -> Self {
-        let mut rng = rand::thread_rng();
+        Self::from_seed(rand::random())
+    }
+
+    pub fn from_seed(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
 
         let mut amount = [0; S];
         let mut attractions = [[0.; S]; S];
@@ -57,12 +72,13 @@ impl<const S: usize> Rules<S> {
             for j in 0..S {
                 attractions[i][j] = rng.gen_range((-1.)..(1.));
             }
-            colors[i] = Self::default_colors_hsla[i % S];
+            colors[i] = Self::default_color(i);
             rep_range[i] = 15.;
             rep_force[i] = -1.0;
         }
 
         return Self {
+            seed,
             amount,
             attractions,
             colors,
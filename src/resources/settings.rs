@@ -1,13 +1,33 @@
 use bevy::prelude::Resource;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
-#[derive(Resource)]
+#[derive(Resource, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
     pub g: f32,
     pub mass: f32,
     pub drag_coef: f32,
     pub max_dist: f32,
     pub max_velocity: f32,
+    // Largest total force on a particle in a single step
+    pub max_force: f32,
     pub edge_mode: EdgeMode,
+    // Particles closer to an edge than `wall_range` are pushed back, up to `wall_strength` right
+    // at the edge
+    pub wall_range: f32,
+    pub wall_strength: f32,
+    pub integrator: Integrator,
+    pub forces: ForceSources,
+    // Fixed iteration and summation order, so runs can be replayed exactly
+    pub deterministic: bool,
+    // Strength of the random thermal kicks every particle gets each tick
+    pub temperature: f32,
+    // Temperature of every group relative to `temperature`, 1 for groups missing here
+    pub group_temperatures: Vec<f32>,
+    // Fraction of the temperature lost every tick, down to `min_temperature`
+    pub cooling_rate: f32,
+    pub min_temperature: f32,
 }
 
 impl Default for Settings {
@@ -18,13 +38,98 @@ impl Default for Settings {
             drag_coef: 0.65,
             max_dist: 80.0,
             max_velocity: 20.0,
+            max_force: 20.0,
             edge_mode: EdgeMode::WRAP,
+            wall_range: 0.0,
+            wall_strength: 1.0,
+            integrator: Integrator::Euler,
+            forces: ForceSources::default(),
+            deterministic: false,
+            temperature: 0.0,
+            group_temperatures: Vec::new(),
+            cooling_rate: 0.0,
+            min_temperature: 0.0,
         }
     }
 }
 
+impl Settings {
+    // Temperature after annealing for `tick` ticks
+    pub fn temperature_at(&self, tick: u64) -> f32 {
+        let cooled = self.temperature * (1. - self.cooling_rate).powf(tick as f32);
+        cooled.max(self.min_temperature.min(self.temperature))
+    }
+
+    pub fn group_temperature(&self, group: usize) -> f32 {
+        self.group_temperatures.get(group).copied().unwrap_or(1.)
+    }
+}
+
+// Which sources of force act on the particles
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForceSources {
+    // Attraction and repulsion between particles
+    pub interactions: bool,
+    pub walls: bool,
+    // The force tool
+    pub tools: bool,
+    pub fields: bool,
+    // Thermal kicks
+    pub noise: bool,
+}
+
+impl Default for ForceSources {
+    fn default() -> Self {
+        Self {
+            interactions: true,
+            walls: true,
+            tools: true,
+            fields: true,
+            noise: true,
+        }
+    }
+}
+
+impl ForceSources {
+    pub fn disable(&mut self, source: ForceSource) {
+        match source {
+            ForceSource::Interactions => self.interactions = false,
+            ForceSource::Walls => self.walls = false,
+            ForceSource::Tools => self.tools = false,
+            ForceSource::Fields => self.fields = false,
+            ForceSource::Noise => self.noise = false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum ForceSource {
+    Interactions,
+    Walls,
+    Tools,
+    Fields,
+    Noise,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, ValueEnum)]
 pub enum EdgeMode {
     WRAP,
     BOUNCE,
     STOP,
 }
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrator {
+    // Semi-implicit Euler, the velocity is updated first and then moves the particle
+    Euler,
+    // Second order, finishes every velocity with the force at the end of the tick
+    VelocityVerlet,
+    // Kick, drift, kick: half of the velocity change before the move and the other half with
+    // the forces between the particles where it ends
+    Leapfrog,
+    // Fourth order Runge-Kutta, finding the forces between all particles at three more
+    // positions of them every tick
+    Rk4,
+}
@@ -0,0 +1,4 @@
+use bevy::prelude::Resource;
+
+#[derive(Resource, Default)]
+pub struct Tick(pub u64);
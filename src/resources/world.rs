@@ -0,0 +1,32 @@
+use bevy::prelude::{Resource, Vec2};
+use serde::Deserialize;
+
+use crate::{WORLD_HEIGHT, WORLD_WIDTH};
+
+// Half extents of the world, particles live within -width..width and -height..height
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(default)]
+pub struct WorldSize {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Default for WorldSize {
+    fn default() -> Self {
+        Self {
+            width: WORLD_WIDTH,
+            height: WORLD_HEIGHT,
+        }
+    }
+}
+
+impl WorldSize {
+    pub fn bounds(&self) -> Vec2 {
+        Vec2::new(self.width as f32, self.height as f32)
+    }
+
+    // Full extent of the world from edge to edge
+    pub fn extent(&self) -> Vec2 {
+        self.bounds() * 2.
+    }
+}
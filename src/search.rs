@@ -0,0 +1,106 @@
+use std::path::PathBuf;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+
+use crate::{
+    evaluation::{evaluate, EvaluationSettings, Score},
+    preset::Preset,
+    resources::{rules::Rules, settings::Settings, world::WorldSize},
+};
+
+pub struct SearchSettings {
+    pub candidates: usize,
+    // Number of best rule sets saved as presets
+    pub top: usize,
+    pub directory: PathBuf,
+    // Particles per group of every candidate, the random default when empty
+    pub amounts: Option<Vec<usize>>,
+    pub evaluation: EvaluationSettings,
+}
+
+// Scores random rule sets in parallel and saves the best ones as presets, returns them best first
+pub fn search<const S: usize>(
+    seed: u64,
+    settings: &Settings,
+    world: &WorldSize,
+    search: &SearchSettings,
+) -> Vec<(Rules<S>, Score)> {
+    // Candidate seeds follow from the search seed, so a search can be repeated
+    let mut rng = StdRng::seed_from_u64(seed);
+    let seeds: Vec<u64> = (0..search.candidates).map(|_| rng.gen()).collect();
+
+    println!(
+        "Evaluating {} rule sets for {} ticks each",
+        search.candidates, search.evaluation.ticks
+    );
+    let mut results: Vec<(Rules<S>, Score)> = seeds
+        .into_par_iter()
+        .map(|seed| {
+            let mut rules = Rules::<S>::from_seed(seed);
+            if let Some(amounts) = &search.amounts {
+                rules.amount.copy_from_slice(amounts);
+            }
+
+            let score = evaluate(rules.clone(), settings.clone(), *world, &search.evaluation).score;
+            println!("Seed {}: {}", seed, score);
+            (rules, score)
+        })
+        .collect();
+
+    results.sort_by(|(_, a), (_, b)| b.total.total_cmp(&a.total));
+    results.truncate(search.top);
+
+    for (rank, (rules, score)) in results.iter().enumerate() {
+        let path = search
+            .directory
+            .join(format!("{:02}-{}.json", rank + 1, rules.seed));
+        match Preset::new(rules, Some(settings)).save(&path) {
+            Ok(()) => println!("#{} {} saved to {}", rank + 1, score, path.display()),
+            Err(err) => eprintln!("Failed to save preset to {}: {}", path.display(), err),
+        }
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn best_rule_sets_are_saved_best_first() {
+        let directory =
+            std::env::temp_dir().join(format!("particle-life-search-{}", std::process::id()));
+        let search_settings = SearchSettings {
+            candidates: 3,
+            top: 2,
+            directory: directory.clone(),
+            amounts: Some(vec![20; 2]),
+            evaluation: EvaluationSettings {
+                ticks: 20,
+                warmup: 5,
+                sample_interval: 5,
+                ..Default::default()
+            },
+        };
+        let world = WorldSize {
+            width: 100,
+            height: 100,
+        };
+
+        let results = search::<2>(9, &Settings::default(), &world, &search_settings);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].1.total >= results[1].1.total);
+
+        for (rank, (rules, _)) in results.iter().enumerate() {
+            assert_eq!(rules.amount, [20; 2]);
+            let path = directory.join(format!("{:02}-{}.json", rank + 1, rules.seed));
+            let preset = Preset::load(&path).unwrap();
+            assert_eq!(preset.seed, rules.seed);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,72 +1,290 @@
+use std::{f32::consts::TAU, path::PathBuf};
+
 use bevy::{
+    app::AppExit,
+    log::{error, info},
     prelude::{
-        default, shape, Assets, Commands, Entity, Mesh, Query, Res, ResMut, Transform, Vec2, With,
-        World,
+        default, shape, App, Assets, Commands, CoreStage, Entity, EventWriter, Handle,
+        IntoSystemDescriptor, Mesh, Plugin, Query, Res, ResMut, Resource, Transform, Vec2, With,
+        Without, World,
     },
     sprite::{ColorMaterial, MaterialMesh2dBundle},
 };
 use bevy_rapier2d::prelude::{AdditionalMassProperties, Damping, ExternalForce, RigidBody};
-use rand::Rng;
+use iyes_loopless::prelude::IntoConditionalSystem;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 use crate::{
-    entity::particle::{GroupId, Particle, ParticleMarker, Position, Velocity},
-    resources::{chunks::Chunks, rules::Rules, settings::Settings},
-    WORLD_HEIGHT, WORLD_WIDTH,
+    chunking::sort_into_chunks,
+    clusters::ClusterPlugin,
+    config::ConfigReloaded,
+    entity::particle::{
+        Energy, Force, GroupId, Particle, ParticleIndex, ParticleMarker, Position, PreviousForce,
+        Velocity,
+    },
+    fields::{FieldPlugin, ForceFields},
+    genome::Genome,
+    interventions::{apply_interventions, Intervention},
+    physics::apply_velocity,
+    resources::{
+        chunks::Chunks,
+        rules::Rules,
+        settings::{EdgeMode, Settings},
+        tick::Tick,
+        world::WorldSize,
+    },
+    snapshot::{PendingRestore, Snapshot, SnapshotParticleQuery},
 };
 
+// Mesh and material shared by all particles of a group, indexed by group id
+#[derive(Resource)]
+pub struct GroupAssets(pub Vec<(Handle<Mesh>, Handle<ColorMaterial>)>);
+
+// Index the next particle spawned while running gets
+#[derive(Resource, Default)]
+pub struct NextParticleIndex(pub u64);
+
+// Everything stepping a simulation, shared by the main world and the universes. Expects the
+// rules, settings and world size as resources
+pub struct SimulationPlugin<const S: usize>;
+
+impl<const S: usize> Plugin for SimulationPlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Tick>()
+            .init_resource::<NextParticleIndex>()
+            .init_resource::<PendingRestore>()
+            .init_resource::<ForceFields>()
+            .add_event::<Intervention>()
+            .add_event::<ConfigReloaded>()
+            .add_plugin(ClusterPlugin::<S>)
+            .add_plugin(FieldPlugin)
+            .add_system(apply_interventions::<S>.label("interventions"))
+            .add_system(configure::<S>)
+            .add_system_to_stage(CoreStage::PreUpdate, index_new_particles)
+            // Every source of force adds to the forces in turn, in the same order every tick
+            .add_system(clear_forces.label("clear_forces").before("interventions"))
+            .add_system(
+                update_rules::<S>
+                    .run_if_resource_exists::<Chunks>()
+                    .label("update")
+                    .label("forces")
+                    .after("interventions"),
+            )
+            .add_system(
+                update_walls
+                    .run_if_resource_exists::<Chunks>()
+                    .label("walls")
+                    .label("forces")
+                    .after("update"),
+            )
+            .add_system(
+                update_temperature::<S>
+                    .run_if_resource_exists::<Chunks>()
+                    .label("temperature")
+                    .label("forces")
+                    .after("walls"),
+            )
+            .add_system(
+                update_edge
+                    .run_if_resource_exists::<Chunks>()
+                    .label("edge")
+                    .after("forces"),
+            )
+            .add_system(
+                apply_velocity::<S>
+                    .run_if_resource_exists::<Chunks>()
+                    .label("apply_velocity")
+                    .after("forces")
+                    .after("edge"),
+            )
+            .add_system(
+                sort_into_chunks
+                    .run_if_resource_exists::<Chunks>()
+                    .label("chunks")
+                    .after("apply_velocity"),
+            )
+            .add_system(
+                advance_tick
+                    .run_if_resource_exists::<Chunks>()
+                    .label("tick")
+                    .after("chunks"),
+            );
+    }
+}
+
 pub fn configure<const S: usize>(
     mut commands: Commands,
     rules: Res<Rules<S>>,
-    settings: Res<Settings>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    (settings, world, chunks): (Res<Settings>, Res<WorldSize>, Option<Res<Chunks>>),
+    mut restore: ResMut<PendingRestore>,
+    (mut meshes, mut materials): (ResMut<Assets<Mesh>>, ResMut<Assets<ColorMaterial>>),
     particle_query: Query<Entity, With<ParticleMarker>>,
 ) {
-    if rules.is_changed() {
-        let chunk_size = settings.max_dist;
-        let chunks_x = (WORLD_WIDTH as f32 * 2. / chunk_size).ceil();
-        let chunks_y = (WORLD_HEIGHT as f32 * 2. / chunk_size).ceil();
-        let chunks = Chunks::new(chunks_x as usize, chunks_y as usize, chunk_size as usize);
-
-        commands.insert_resource(chunks);
+    // Chunks are as large as the interaction distance, so changing it needs new chunks as well
+    let resized = chunks.is_some_and(|chunks| chunks.size != settings.max_dist as usize);
+    if rules.is_changed() || world.is_changed() || resized {
+        let mut chunks = Chunks::covering(&world, settings.max_dist);
 
         particle_query.for_each(|particle| commands.entity(particle).despawn());
 
-        let mut rng = rand::thread_rng();
-
-        for group_id in 0..S {
-            let mesh = meshes.add(Mesh::from(shape::Circle {
-                radius: 2.0,
-                vertices: 32usize,
-            }));
-            let material = materials.add(ColorMaterial::from(rules.colors[group_id]));
-
-            for _ in 0..rules.amount[group_id] {
-                let x = rng.gen_range(-(WORLD_WIDTH as f32)..(WORLD_WIDTH as f32));
-                let y = rng.gen_range(-(WORLD_HEIGHT as f32)..(WORLD_HEIGHT as f32));
-
-                commands
-                    .spawn(Particle {
-                        mesh_bundle: MaterialMesh2dBundle {
-                            mesh: mesh.clone().into(),
-                            material: material.clone().into(),
-                            transform: Transform::from_xyz(x, y, 0.0),
-                            ..default()
-                        },
-                        particle_marker: ParticleMarker,
-                        velocity: Velocity(Vec2::ZERO),
-                        position: Position(Vec2::new(x, y)),
-                        group_id: GroupId(group_id),
-                    })
-                    .insert(AdditionalMassProperties::Mass(settings.mass))
-                    .insert(Damping {
-                        linear_damping: settings.drag_coef,
-                        angular_damping: settings.drag_coef,
-                    })
-                    .insert(GroupId(group_id));
+        let handles: Vec<(Handle<Mesh>, Handle<ColorMaterial>)> = (0..S)
+            .map(|group_id| {
+                let mesh = meshes.add(Mesh::from(shape::Circle {
+                    radius: 2.0,
+                    vertices: 32usize,
+                }));
+                let material = materials.add(ColorMaterial::from(rules.colors[group_id]));
+                (mesh, material)
+            })
+            .collect();
+        commands.insert_resource(GroupAssets(handles.clone()));
+
+        if let Some(snapshot) = restore.0.take() {
+            commands.insert_resource(Tick(snapshot.tick));
+            let next_index = snapshot.particles.iter().map(|particle| particle.index + 1);
+            commands.insert_resource(NextParticleIndex(next_index.max().unwrap_or_default()));
+
+            for particle in snapshot.particles {
+                if particle.group >= S {
+                    continue;
+                }
+
+                let (mesh, material) = &handles[particle.group];
+                let position = Position(Vec2::from(particle.position));
+                let entity = spawn_particle(
+                    &mut commands,
+                    &settings,
+                    mesh,
+                    material,
+                    position.0,
+                    Vec2::from(particle.velocity),
+                    particle.group,
+                );
+                commands.entity(entity).insert((
+                    ParticleIndex(particle.index),
+                    PreviousForce(particle.previous_force.map(Vec2::from)),
+                ));
+                if let Some(genome) = particle.genome.and_then(|genome| genome.to_genome::<S>()) {
+                    commands.entity(entity).insert(genome);
+                }
+                if let Some(energy) = particle.energy {
+                    commands.entity(entity).insert(Energy(energy));
+                }
+
+                // Restored particles interact from the very first tick
+                chunks.insert_particle(entity, position, GroupId(particle.group));
+            }
+            if settings.deterministic {
+                chunks.sort();
             }
+            commands.insert_resource(chunks);
+            return;
+        }
+
+        commands.insert_resource(Tick::default());
+
+        let positions = spawn_positions(&rules, &world);
+        commands.insert_resource(NextParticleIndex(positions.len() as u64));
+        for (index, (position, group_id)) in positions.into_iter().enumerate() {
+            let (mesh, material) = &handles[group_id];
+            let entity = spawn_particle(
+                &mut commands,
+                &settings,
+                mesh,
+                material,
+                position,
+                Vec2::ZERO,
+                group_id,
+            );
+            commands.entity(entity).insert(ParticleIndex(index as u64));
+
+            // New particles interact from the very first tick as well, or the force they missed
+            // on it would linger in the forces second order integrators carry over
+            chunks.insert_particle(entity, Position(position), GroupId(group_id));
+        }
+        if settings.deterministic {
+            chunks.sort();
+        }
+        commands.insert_resource(chunks);
+    }
+}
+
+// Starting position and group of every particle, the same for every run with the same seed
+pub fn spawn_positions<const S: usize>(rules: &Rules<S>, world: &WorldSize) -> Vec<(Vec2, usize)> {
+    let mut rng = StdRng::seed_from_u64(rules.seed);
+
+    let mut positions = Vec::new();
+    for group_id in 0..S {
+        for _ in 0..rules.amount[group_id] {
+            let x = rng.gen_range(-world.bounds().x..world.bounds().x);
+            let y = rng.gen_range(-world.bounds().y..world.bounds().y);
+            positions.push((Vec2::new(x, y), group_id));
         }
     }
+    positions
+}
+
+pub fn spawn_particle(
+    commands: &mut Commands,
+    settings: &Settings,
+    mesh: &Handle<Mesh>,
+    material: &Handle<ColorMaterial>,
+    position: Vec2,
+    velocity: Vec2,
+    group_id: usize,
+) -> Entity {
+    commands
+        .spawn(Particle {
+            mesh_bundle: MaterialMesh2dBundle {
+                mesh: mesh.clone().into(),
+                material: material.clone(),
+                transform: Transform::from_xyz(position.x, position.y, 0.0),
+                ..default()
+            },
+            particle_marker: ParticleMarker,
+            velocity: Velocity(velocity),
+            position: Position(position),
+            group_id: GroupId(group_id),
+            force: Force::default(),
+            previous_force: PreviousForce::default(),
+        })
+        .insert(AdditionalMassProperties::Mass(settings.mass))
+        .insert(Damping {
+            linear_damping: settings.drag_coef,
+            angular_damping: settings.drag_coef,
+        })
+        .insert(GroupId(group_id))
+        .id()
+}
+
+type UnindexedParticleQuery<'w, 's> = Query<
+    'w,
+    's,
+    (Entity, &'static Position, &'static GroupId),
+    (With<ParticleMarker>, Without<ParticleIndex>),
+>;
+
+// Particles spawned while running, by tools or division, get the next indices in an order that
+// doesn't depend on their entities
+pub fn index_new_particles(
+    mut commands: Commands,
+    mut next_index: ResMut<NextParticleIndex>,
+    particle_query: UnindexedParticleQuery,
+) {
+    let mut particles: Vec<(Entity, &Position, &GroupId)> = particle_query.iter().collect();
+    particles.sort_by(|(_, a_pos, a_id), (_, b_pos, b_id)| {
+        a_pos
+            .0
+            .x
+            .total_cmp(&b_pos.0.x)
+            .then(a_pos.0.y.total_cmp(&b_pos.0.y))
+            .then(a_id.0.cmp(&b_id.0))
+    });
+
+    for (entity, _, _) in particles {
+        commands.entity(entity).insert(ParticleIndex(next_index.0));
+        next_index.0 += 1;
+    }
 }
 
 /*pub fn update_rules_chunked<const S: usize>(
@@ -152,45 +370,197 @@ pub fn update_rules_old<const S: usize>(
     });
 }*/
 
+// Starts the forces of the tick over, every source of force adds to them afterwards
+pub fn clear_forces(mut particle_query: Query<&mut Force, With<ParticleMarker>>) {
+    particle_query.par_for_each_mut(64, |mut force| force.0 = Vec2::ZERO);
+}
+
+type InteractingParticleQuery<'w, 's, const S: usize> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static mut Force,
+        &'static Position,
+        &'static GroupId,
+        Option<&'static Genome<S>>,
+    ),
+    With<ParticleMarker>,
+>;
+
 pub fn update_rules<const S: usize>(
     chunks: Res<Chunks>,
     rules: Res<Rules<S>>,
     settings: Res<Settings>,
-    mut particle_query: Query<(&mut Velocity, &Position, &GroupId), With<ParticleMarker>>,
+    mut particle_query: InteractingParticleQuery<S>,
 ) {
-    particle_query.par_for_each_mut(16, |(mut vel, pos, id)| {
-        let mut combined = Vec2::ZERO;
+    if !settings.forces.interactions {
+        return;
+    }
 
-        for chunk in chunks.get_chunks_around(pos.0.x, pos.0.y) {
-            for (other_pos, other_id) in &chunk.particles {
-                let vec = pos.0 - other_pos.0;
-                let dist = vec.length();
-                if dist == 0.0 || dist > settings.max_dist {
-                    continue;
-                }
+    // Every particle only adds to its own force, summed in chunk order, so running in parallel
+    // stays reproducible once the chunks are sorted
+    particle_query.par_for_each_mut(16, |(entity, mut force, pos, id, genome)| {
+        force.0 += particle_force(&rules, &settings, &chunks, entity, pos.0, id.0, genome);
+    });
+}
 
-                let modifier = if dist <= rules.rep_range[id.0] {
-                    rules.rep_force[id.0] * dist / rules.rep_range[id.0]
-                } else {
-                    let attraction = rules.attractions[id.0][other_id.0];
-                    let half_dist = settings.max_dist / 2.;
-                    if dist < half_dist {
-                        attraction * dist / half_dist
-                    } else {
-                        attraction * (dist - half_dist) / half_dist
-                    }
-                };
+// Force the particles around `pos` exert on the particle `entity` of group `id`. Particles with
+// a genome interact by their own copy of the rules
+pub fn particle_force<const S: usize>(
+    rules: &Rules<S>,
+    settings: &Settings,
+    chunks: &Chunks,
+    entity: Entity,
+    pos: Vec2,
+    id: usize,
+    genome: Option<&Genome<S>>,
+) -> Vec2 {
+    match genome {
+        Some(genome) => genome_force(genome, settings, chunks, entity, pos),
+        None => interaction_force(rules, settings, chunks, entity, pos, id),
+    }
+}
+
+// Sum of the forces the particles around `pos` exert on the particle `entity` of group `id`
+pub fn interaction_force<const S: usize>(
+    rules: &Rules<S>,
+    settings: &Settings,
+    chunks: &Chunks,
+    entity: Entity,
+    pos: Vec2,
+    id: usize,
+) -> Vec2 {
+    genome_force(
+        &Genome::from_rules(rules, id),
+        settings,
+        chunks,
+        entity,
+        pos,
+    )
+}
+
+// Sum of the forces the particles around `pos` exert on the particle `entity` interacting by
+// `genome`. `pos` doesn't need to be where the chunks have the particle, it never acts on itself
+pub fn genome_force<const S: usize>(
+    genome: &Genome<S>,
+    settings: &Settings,
+    chunks: &Chunks,
+    entity: Entity,
+    pos: Vec2,
+) -> Vec2 {
+    let mut combined = Vec2::ZERO;
 
-                let dir = vec.normalize_or_zero();
-                let strength = settings.g * modifier;
-                combined -= Vec2::new(dir.x, dir.y) * strength;
+    for chunk in chunks.get_chunks_around(pos.x, pos.y) {
+        for (other, other_pos, other_id) in &chunk.particles {
+            let vec = pos - other_pos.0;
+            let dist = vec.length();
+            if *other == entity || dist == 0.0 || dist > settings.max_dist {
+                continue;
             }
+
+            let modifier = if dist <= genome.rep_range {
+                genome.rep_force * dist / genome.rep_range
+            } else {
+                let attraction = genome.attractions[other_id.0];
+                let half_dist = settings.max_dist / 2.;
+                if dist < half_dist {
+                    attraction * dist / half_dist
+                } else {
+                    attraction * (dist - half_dist) / half_dist
+                }
+            };
+
+            let dir = vec.normalize_or_zero();
+            let strength = settings.g * modifier;
+            combined -= Vec2::new(dir.x, dir.y) * strength;
         }
+    }
+
+    combined
+}
+
+pub fn update_temperature<const S: usize>(
+    rules: Res<Rules<S>>,
+    settings: Res<Settings>,
+    tick: Res<Tick>,
+    mut particle_query: Query<(&ParticleIndex, &mut Force, &GroupId), With<ParticleMarker>>,
+) {
+    let temperature = settings.temperature_at(tick.0);
+    if temperature <= 0. || !settings.forces.noise {
+        return;
+    }
 
-        vel.0 += combined.clamp_length(0.0, settings.max_velocity);
+    particle_query.par_for_each_mut(64, |(index, mut force, id)| {
+        let group_temperature = temperature * settings.group_temperature(id.0);
+        force.0 += thermal_kick(group_temperature, rules.seed, tick.0, index.0);
     });
 }
 
+// Random kick of a particle, the same for the same seed, tick and particle index so runs replay
+// exactly
+pub fn thermal_kick(temperature: f32, seed: u64, tick: u64, index: u64) -> Vec2 {
+    if temperature <= 0. {
+        return Vec2::ZERO;
+    }
+
+    let mut rng = StdRng::seed_from_u64(
+        seed ^ tick.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ index.wrapping_mul(0xbf58_476d_1ce4_e5b9),
+    );
+    // Normally distributed along both axes through the Box-Muller transform
+    let radius = (-2. * (1. - rng.gen::<f32>()).ln()).sqrt();
+    let angle = rng.gen::<f32>() * TAU;
+    Vec2::from_angle(angle) * radius * temperature.sqrt()
+}
+
+pub fn advance_tick(mut tick: ResMut<Tick>) {
+    tick.0 += 1;
+}
+
+// Ends the run after a fixed number of steps, optionally saving where it got to
+#[derive(Resource)]
+pub struct StepLimit {
+    pub steps: u64,
+    pub output: Option<PathBuf>,
+    done: u64,
+}
+
+impl StepLimit {
+    pub fn new(steps: u64, output: Option<PathBuf>) -> Self {
+        Self {
+            steps,
+            output,
+            done: 0,
+        }
+    }
+}
+
+pub fn exit_after_steps<const S: usize>(
+    rules: Res<Rules<S>>,
+    settings: Res<Settings>,
+    world: Res<WorldSize>,
+    tick: Res<Tick>,
+    mut limit: ResMut<StepLimit>,
+    mut exit: EventWriter<AppExit>,
+    particle_query: SnapshotParticleQuery<S>,
+) {
+    limit.done += 1;
+    if limit.done < limit.steps {
+        return;
+    }
+
+    if let Some(path) = &limit.output {
+        let snapshot = Snapshot::capture(&rules, &settings, &world, tick.0, particle_query.iter());
+        match snapshot.save(path) {
+            Ok(()) => info!("Saved snapshot to {}", path.display()),
+            Err(err) => error!("Failed to save snapshot to {}: {}", path.display(), err),
+        }
+    }
+
+    info!("Finished {} steps", limit.steps);
+    exit.send(AppExit);
+}
+
 pub fn update_repulsion(
     settings: Res<Settings>,
     mut query: Query<(&mut Velocity, &mut Transform), With<ParticleMarker>>,
@@ -243,43 +613,75 @@ pub fn update_repulsion(
     }
 }*/
 
+pub fn update_walls(
+    settings: Res<Settings>,
+    world: Res<WorldSize>,
+    mut particle_query: Query<(&mut Force, &Position), With<ParticleMarker>>,
+) {
+    if settings.wall_range <= 0. || !settings.forces.walls {
+        return;
+    }
+
+    particle_query.par_for_each_mut(64, |(mut force, pos)| {
+        force.0 += wall_force(&settings, world.bounds(), pos.0);
+    });
+}
+
+// Push away from the edges, growing from nothing at `wall_range` to `wall_strength` at the edge
+pub fn wall_force(settings: &Settings, bounds: Vec2, pos: Vec2) -> Vec2 {
+    if settings.wall_range <= 0. || !settings.forces.walls {
+        return Vec2::ZERO;
+    }
+
+    let push = |to_edge: f32| (1. - to_edge / settings.wall_range).max(0.) * settings.wall_strength;
+    Vec2::new(
+        push(bounds.x + pos.x) - push(bounds.x - pos.x),
+        push(bounds.y + pos.y) - push(bounds.y - pos.y),
+    )
+}
+
 pub fn update_edge(
     settings: Res<Settings>,
+    world: Res<WorldSize>,
     mut query: Query<(&mut Velocity, &mut Position), With<ParticleMarker>>,
 ) {
-    let x_bound = WORLD_WIDTH as f32;
-    let y_bound = WORLD_HEIGHT as f32;
-
     for (mut vel, mut pos) in query.iter_mut() {
-        if pos.0.x.abs() > x_bound {
-            match settings.edge_mode {
-                crate::resources::settings::EdgeMode::WRAP => {
-                    pos.0.x = -(pos.0.x.signum() * 2.0 * x_bound - pos.0.x);
-                }
-                crate::resources::settings::EdgeMode::BOUNCE => {
-                    pos.0.x = pos.0.x.signum() * 2.0 * x_bound - pos.0.x;
-                    vel.0.x *= -1.0;
-                }
-                crate::resources::settings::EdgeMode::STOP => {
-                    pos.0.x = pos.0.x.signum() * x_bound;
-                    vel.0.x = 0.0;
-                }
+        apply_edge(settings.edge_mode, world.bounds(), &mut pos.0, &mut vel.0);
+    }
+}
+
+pub fn apply_edge(edge_mode: EdgeMode, bounds: Vec2, pos: &mut Vec2, vel: &mut Vec2) {
+    let x_bound = bounds.x;
+    let y_bound = bounds.y;
+
+    if pos.x.abs() > x_bound {
+        match edge_mode {
+            EdgeMode::WRAP => {
+                pos.x = -(pos.x.signum() * 2.0 * x_bound - pos.x);
+            }
+            EdgeMode::BOUNCE => {
+                pos.x = pos.x.signum() * 2.0 * x_bound - pos.x;
+                vel.x *= -1.0;
+            }
+            EdgeMode::STOP => {
+                pos.x = pos.x.signum() * x_bound;
+                vel.x = 0.0;
             }
         }
+    }
 
-        if pos.0.y.abs() > y_bound {
-            match settings.edge_mode {
-                crate::resources::settings::EdgeMode::WRAP => {
-                    pos.0.y = -(pos.0.y.signum() * 2.0 * y_bound - pos.0.y);
-                }
-                crate::resources::settings::EdgeMode::BOUNCE => {
-                    pos.0.y = pos.0.y.signum() * 2.0 * y_bound - pos.0.y;
-                    vel.0.y *= -1.0;
-                }
-                crate::resources::settings::EdgeMode::STOP => {
-                    pos.0.y = pos.0.y.signum() * y_bound;
-                    vel.0.y = 0.0;
-                }
+    if pos.y.abs() > y_bound {
+        match edge_mode {
+            EdgeMode::WRAP => {
+                pos.y = -(pos.y.signum() * 2.0 * y_bound - pos.y);
+            }
+            EdgeMode::BOUNCE => {
+                pos.y = pos.y.signum() * 2.0 * y_bound - pos.y;
+                vel.y *= -1.0;
+            }
+            EdgeMode::STOP => {
+                pos.y = pos.y.signum() * y_bound;
+                vel.y = 0.0;
             }
         }
     }
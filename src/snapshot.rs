@@ -0,0 +1,489 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    camera::{CameraBookmark, CameraBookmarks},
+    entity::particle::{
+        Energy, GroupId, ParticleIndex, ParticleMarker, Position, PreviousForce, Velocity,
+    },
+    genome::{Genome, GenomeSnapshot},
+    resources::{actions::Action, rules::Rules, settings::Settings, tick::Tick, world::WorldSize},
+};
+
+const SNAPSHOT_VERSION: u32 = 7;
+
+#[derive(Resource)]
+pub struct SnapshotSettings {
+    pub directory: PathBuf,
+    // Write quicksaves as JSON instead of the compact binary format
+    pub json: bool,
+}
+
+impl Default for SnapshotSettings {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("snapshots"),
+            json: false,
+        }
+    }
+}
+
+impl SnapshotSettings {
+    pub fn quicksave_path(&self) -> PathBuf {
+        let extension = if self.json { "json" } else { "snap" };
+        self.directory.join(format!("quicksave.{}", extension))
+    }
+}
+
+// Snapshot waiting to be spawned by `configure` once its rules have been inserted
+#[derive(Resource, Default)]
+pub struct PendingRestore(pub Option<Snapshot>);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    pub version: u32,
+    pub seed: u64,
+    pub tick: u64,
+    pub world_width: usize,
+    pub world_height: usize,
+    pub rules: RulesSnapshot,
+    pub settings: Settings,
+    pub particles: Vec<ParticleSnapshot>,
+    pub camera_bookmarks: Vec<Option<CameraBookmark>>,
+}
+
+// `Rules` with its const sized arrays flattened, so snapshots don't depend on the group count
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RulesSnapshot {
+    pub amount: Vec<usize>,
+    pub attractions: Vec<Vec<f32>>,
+    pub colors: Vec<Color>,
+    pub rep_range: Vec<f32>,
+    pub rep_force: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ParticleSnapshot {
+    pub index: u64,
+    pub position: [f32; 2],
+    pub velocity: [f32; 2],
+    // Integrators carrying the force over between ticks take the same next step after a restore
+    pub previous_force: Option<[f32; 2]>,
+    pub group: usize,
+    // Evolved genome of runs with genomes, restored instead of drawn again
+    pub genome: Option<GenomeSnapshot>,
+    // Energy left in ecology runs
+    pub energy: Option<f32>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Binary(bincode::Error),
+    Json(serde_json::Error),
+    Version(u32),
+    GroupCount { expected: usize, found: usize },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Binary(err) => write!(f, "invalid snapshot: {}", err),
+            Self::Json(err) => write!(f, "invalid snapshot: {}", err),
+            Self::Version(version) => write!(f, "unsupported snapshot version {}", version),
+            Self::GroupCount { expected, found } => write!(
+                f,
+                "snapshot has {} groups but the simulation has {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(err: bincode::Error) -> Self {
+        Self::Binary(err)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+// Everything a snapshot keeps of a particle
+pub type SnapshotParticle<'a, const S: usize> = (
+    &'a ParticleIndex,
+    &'a Position,
+    &'a Velocity,
+    &'a PreviousForce,
+    &'a GroupId,
+    Option<&'a Genome<S>>,
+    Option<&'a Energy>,
+);
+
+pub type SnapshotParticleQuery<'w, 's, const S: usize> = Query<
+    'w,
+    's,
+    (
+        &'static ParticleIndex,
+        &'static Position,
+        &'static Velocity,
+        &'static PreviousForce,
+        &'static GroupId,
+        Option<&'static Genome<S>>,
+        Option<&'static Energy>,
+    ),
+    With<ParticleMarker>,
+>;
+
+impl<const S: usize> From<&Rules<S>> for RulesSnapshot {
+    fn from(rules: &Rules<S>) -> Self {
+        Self {
+            amount: rules.amount.to_vec(),
+            attractions: rules.attractions.iter().map(|row| row.to_vec()).collect(),
+            colors: rules.colors.to_vec(),
+            rep_range: rules.rep_range.to_vec(),
+            rep_force: rules.rep_force.to_vec(),
+        }
+    }
+}
+
+impl RulesSnapshot {
+    pub fn to_rules<const S: usize>(&self, seed: u64) -> Result<Rules<S>, SnapshotError> {
+        let found = self.amount.len();
+        let consistent = self.attractions.len() == found
+            && self.attractions.iter().all(|row| row.len() == found)
+            && self.colors.len() == found
+            && self.rep_range.len() == found
+            && self.rep_force.len() == found;
+        if found != S || !consistent {
+            return Err(SnapshotError::GroupCount { expected: S, found });
+        }
+
+        let mut attractions = [[0.; S]; S];
+        for (row, values) in attractions.iter_mut().zip(&self.attractions) {
+            row.copy_from_slice(values);
+        }
+
+        Ok(Rules::new(
+            seed,
+            self.amount.clone().try_into().unwrap(),
+            attractions,
+            self.colors.clone().try_into().unwrap(),
+            self.rep_range.clone().try_into().unwrap(),
+            self.rep_force.clone().try_into().unwrap(),
+        ))
+    }
+}
+
+impl Snapshot {
+    pub fn capture<'a, const S: usize>(
+        rules: &Rules<S>,
+        settings: &Settings,
+        world: &WorldSize,
+        tick: u64,
+        particles: impl Iterator<Item = SnapshotParticle<'a, S>>,
+    ) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            seed: rules.seed,
+            tick,
+            world_width: world.width,
+            world_height: world.height,
+            rules: RulesSnapshot::from(rules),
+            settings: settings.clone(),
+            particles: particles
+                .map(
+                    |(index, pos, vel, previous, id, genome, energy)| ParticleSnapshot {
+                        index: index.0,
+                        position: pos.0.to_array(),
+                        velocity: vel.0.to_array(),
+                        previous_force: previous.0.map(|force| force.to_array()),
+                        group: id.0,
+                        genome: genome.map(GenomeSnapshot::from),
+                        energy: energy.map(|energy| energy.0),
+                    },
+                )
+                .collect(),
+            camera_bookmarks: Vec::new(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+
+        let bytes = if is_json(path) {
+            serde_json::to_vec_pretty(self)?
+        } else {
+            bincode::serialize(self)?
+        };
+        fs::write(path, bytes)?;
+
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+        load_versioned(path, SNAPSHOT_VERSION)
+    }
+
+    pub fn world(&self) -> WorldSize {
+        WorldSize {
+            width: self.world_width,
+            height: self.world_height,
+        }
+    }
+}
+
+// The version every saved file starts with, read before the rest so a file of another version is
+// refused as such instead of failing to decode
+#[derive(Deserialize)]
+struct VersionHeader {
+    version: u32,
+}
+
+pub(crate) fn load_versioned<T: DeserializeOwned>(
+    path: &Path,
+    version: u32,
+) -> Result<T, SnapshotError> {
+    let bytes = fs::read(path)?;
+
+    let header: VersionHeader = if is_json(path) {
+        serde_json::from_slice(&bytes)?
+    } else {
+        bincode::deserialize(&bytes)?
+    };
+    if header.version != version {
+        return Err(SnapshotError::Version(header.version));
+    }
+
+    Ok(if is_json(path) {
+        serde_json::from_slice(&bytes)?
+    } else {
+        bincode::deserialize(&bytes)?
+    })
+}
+
+pub(crate) fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+}
+
+// Replaces the running simulation with the snapshot, the particles are respawned by `configure`
+pub fn restore_snapshot<const S: usize>(
+    commands: &mut Commands,
+    restore: &mut PendingRestore,
+    snapshot: Snapshot,
+) -> Result<(), SnapshotError> {
+    let rules = snapshot.rules.to_rules::<S>(snapshot.seed)?;
+
+    commands.insert_resource(rules);
+    commands.insert_resource(snapshot.settings.clone());
+    commands.insert_resource(snapshot.world());
+    let bookmarks = CameraBookmarks::from(snapshot.camera_bookmarks.as_slice());
+    if !bookmarks.is_empty() {
+        commands.insert_resource(bookmarks);
+    }
+    restore.0 = Some(snapshot);
+
+    Ok(())
+}
+
+pub fn quicksave<const S: usize>(
+    rules: Res<Rules<S>>,
+    (settings, world): (Res<Settings>, Res<WorldSize>),
+    tick: Res<Tick>,
+    snapshot_settings: Res<SnapshotSettings>,
+    bookmarks: Res<CameraBookmarks>,
+    action_query: Query<&ActionState<Action>>,
+    particle_query: SnapshotParticleQuery<S>,
+) {
+    let action_state = action_query.single();
+
+    if action_state.just_pressed(Action::QuickSave) {
+        let path = snapshot_settings.quicksave_path();
+        let mut snapshot =
+            Snapshot::capture(&rules, &settings, &world, tick.0, particle_query.iter());
+        snapshot.camera_bookmarks = bookmarks.0.to_vec();
+        match snapshot.save(&path) {
+            Ok(()) => info!("Saved snapshot to {}", path.display()),
+            Err(err) => error!("Failed to save snapshot to {}: {}", path.display(), err),
+        }
+    }
+}
+
+pub fn quickload<const S: usize>(
+    mut commands: Commands,
+    snapshot_settings: Res<SnapshotSettings>,
+    mut restore: ResMut<PendingRestore>,
+    action_query: Query<&ActionState<Action>>,
+) {
+    let action_state = action_query.single();
+
+    if action_state.just_pressed(Action::QuickLoad) {
+        let path = snapshot_settings.quicksave_path();
+        let result = Snapshot::load(&path)
+            .and_then(|snapshot| restore_snapshot::<S>(&mut commands, &mut restore, snapshot));
+        match result {
+            Ok(()) => info!("Loaded snapshot from {}", path.display()),
+            Err(err) => error!("Failed to load snapshot from {}: {}", path.display(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        let rules = Rules::<3>::from_seed(7);
+        let genome = Genome::from_rules(&rules, 2);
+        let particles = [
+            (
+                ParticleIndex(0),
+                Position(Vec2::new(-12.5, 40.)),
+                Velocity(Vec2::new(0.25, -1.)),
+                PreviousForce(None),
+                GroupId(0),
+                None,
+                Some(Energy(0.4)),
+            ),
+            (
+                ParticleIndex(5),
+                Position(Vec2::new(300., -0.5)),
+                Velocity(Vec2::ZERO),
+                PreviousForce(Some(Vec2::new(0.1, 0.2))),
+                GroupId(2),
+                Some(genome),
+                None,
+            ),
+        ];
+        let world = WorldSize {
+            width: 320,
+            height: 240,
+        };
+        Snapshot::capture(
+            &rules,
+            &Settings::default(),
+            &world,
+            42,
+            particles
+                .iter()
+                .map(|(index, pos, vel, previous, id, genome, energy)| {
+                    (
+                        index,
+                        pos,
+                        vel,
+                        previous,
+                        id,
+                        genome.as_ref(),
+                        energy.as_ref(),
+                    )
+                }),
+        )
+    }
+
+    fn round_trip(file: &str) -> Snapshot {
+        let path = std::env::temp_dir()
+            .join(format!("particle-life-{}", std::process::id()))
+            .join(file);
+        snapshot().save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    fn assert_same(loaded: &Snapshot) {
+        let saved = snapshot();
+        assert_eq!(loaded.seed, saved.seed);
+        assert_eq!(loaded.tick, 42);
+        assert_eq!((loaded.world().width, loaded.world().height), (320, 240));
+        assert!(loaded.settings == saved.settings);
+
+        let rules = loaded.rules.to_rules::<3>(loaded.seed).unwrap();
+        let original = Rules::<3>::from_seed(7);
+        assert_eq!(rules.attractions, original.attractions);
+        assert_eq!(rules.amount, original.amount);
+        assert_eq!(rules.colors, original.colors);
+
+        assert_eq!(loaded.particles.len(), 2);
+        for (loaded, saved) in loaded.particles.iter().zip(&saved.particles) {
+            assert_eq!(loaded.index, saved.index);
+            assert_eq!(loaded.position, saved.position);
+            assert_eq!(loaded.velocity, saved.velocity);
+            assert_eq!(loaded.previous_force, saved.previous_force);
+            assert_eq!(loaded.group, saved.group);
+            assert_eq!(loaded.energy, saved.energy);
+            let genome = |particle: &ParticleSnapshot| {
+                particle
+                    .genome
+                    .as_ref()
+                    .and_then(GenomeSnapshot::to_genome::<3>)
+                    .map(|genome| (genome.attractions, genome.rep_range, genome.rep_force))
+            };
+            assert_eq!(genome(loaded), genome(saved));
+        }
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        assert_same(&round_trip("snapshot.bin"));
+    }
+
+    #[test]
+    fn json_round_trip() {
+        assert_same(&round_trip("snapshot.json"));
+    }
+
+    // Loads a file of an older version, laid out differently past the version
+    fn load_old(file: &str, bytes: Vec<u8>) -> Result<Snapshot, SnapshotError> {
+        let directory = std::env::temp_dir().join(format!("particle-life-{}", std::process::id()));
+        let path = directory.join(file);
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(&path, bytes).unwrap();
+        let loaded = Snapshot::load(&path);
+        fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let old = SNAPSHOT_VERSION - 1;
+        let json = serde_json::json!({ "version": old, "particles": "elsewhere" });
+        let loaded = load_old("old.json", serde_json::to_vec(&json).unwrap());
+        assert!(matches!(loaded, Err(SnapshotError::Version(version)) if version == old));
+
+        let binary = bincode::serialize(&(old, "elsewhere", 1.5f32)).unwrap();
+        let loaded = load_old("old.bin", binary);
+        assert!(matches!(loaded, Err(SnapshotError::Version(version)) if version == old));
+    }
+
+    #[test]
+    fn rules_of_another_group_count_are_refused() {
+        assert!(matches!(
+            snapshot().rules.to_rules::<4>(7),
+            Err(SnapshotError::GroupCount {
+                expected: 4,
+                found: 3
+            })
+        ));
+    }
+}
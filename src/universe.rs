@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::AssetPlugin,
+    core::CorePlugin,
+    ecs::event::Events,
+    prelude::*,
+    sprite::{ColorMaterial, MaterialMesh2dBundle},
+};
+
+use crate::{
+    config::ConfigReloaded,
+    entity::particle::{GroupId, ParticleMarker, Position, Velocity},
+    interventions::Intervention,
+    resources::{chunks::Chunks, rules::Rules, settings::Settings, tick::Tick, world::WorldSize},
+    simulation::SimulationPlugin,
+};
+
+// Space left between the main world and the universes next to it
+pub const UNIVERSE_GAP: f32 = 100.;
+
+// A simulation in an ECS world of its own with its own rules, settings, bounds and chunks,
+// stepped by the same plugins as the main world. Its particles never meet those of any other
+// universe
+#[derive(Component)]
+pub struct Universe<const S: usize> {
+    pub world: World,
+    schedule: Schedule,
+}
+
+impl<const S: usize> Universe<S> {
+    // The simulation plugins without window or renderer. Plugins the main world runs with on
+    // top of them are added to the app before it becomes a universe with `from_app`
+    pub fn app(rules: Rules<S>, settings: Settings, world: WorldSize) -> App {
+        let mut app = App::new();
+        app.add_plugin(CorePlugin::default())
+            .add_plugin(AssetPlugin::default())
+            .add_asset::<Mesh>()
+            .add_asset::<ColorMaterial>()
+            .insert_resource(rules)
+            .insert_resource(settings)
+            .insert_resource(world)
+            .add_plugin(SimulationPlugin::<S>);
+        app
+    }
+
+    pub fn new(rules: Rules<S>, settings: Settings, world: WorldSize) -> Self {
+        Self::from_app(Self::app(rules, settings, world))
+    }
+
+    pub fn from_app(mut app: App) -> Self {
+        Self {
+            world: std::mem::take(&mut app.world),
+            schedule: std::mem::take(&mut app.schedule),
+        }
+    }
+
+    // One tick, the first one spawns the particles
+    pub fn step(&mut self) {
+        self.schedule.run(&mut self.world);
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.world.get_resource::<Tick>().map_or(0, |tick| tick.0)
+    }
+
+    pub fn rules(&self) -> &Rules<S> {
+        self.world.resource()
+    }
+
+    pub fn settings(&self) -> &Settings {
+        self.world.resource()
+    }
+
+    pub fn size(&self) -> WorldSize {
+        *self.world.resource()
+    }
+
+    // Missing until the first step
+    pub fn chunks(&self) -> Option<&Chunks> {
+        self.world.get_resource()
+    }
+
+    // Position, velocity and group of a particle
+    pub fn particle(&self, entity: Entity) -> Option<(Vec2, Vec2, usize)> {
+        let particle = self.world.get_entity(entity)?;
+        Some((
+            particle.get::<Position>()?.0,
+            particle.get::<Velocity>()?.0,
+            particle.get::<GroupId>()?.0,
+        ))
+    }
+
+    // Entity, position, velocity and group of every particle
+    pub fn particles(&mut self) -> Vec<(Entity, Vec2, Vec2, usize)> {
+        let mut particle_query = self
+            .world
+            .query_filtered::<(Entity, &Position, &Velocity, &GroupId), With<ParticleMarker>>();
+        particle_query
+            .iter(&self.world)
+            .map(|(entity, pos, vel, id)| (entity, pos.0, vel.0, id.0))
+            .collect()
+    }
+
+    // Offset of the universe at `index` in a row to the right of a main world of size `world`
+    pub fn offset_in_row(index: usize, world: &WorldSize) -> Vec2 {
+        Vec2::new((index + 1) as f32 * (world.extent().x + UNIVERSE_GAP), 0.)
+    }
+}
+
+// Particles of a universe drawn in the main world, each by a child of the universe entity.
+// Indexed by the entity of the particle in the universe
+#[derive(Component, Default)]
+pub struct UniverseView {
+    views: HashMap<Entity, Entity>,
+    mesh: Option<Handle<Mesh>>,
+    // Shared by all particles of a group, along with the color they were made for
+    materials: Vec<(Color, Handle<ColorMaterial>)>,
+}
+
+#[derive(Bundle)]
+pub struct UniverseBundle<const S: usize> {
+    pub universe: Universe<S>,
+    pub view: UniverseView,
+    // Where the center of the universe is drawn, particle positions stay relative to it
+    #[bundle]
+    pub spatial: SpatialBundle,
+}
+
+impl<const S: usize> UniverseBundle<S> {
+    pub fn new(universe: Universe<S>, offset: Vec2) -> Self {
+        Self {
+            universe,
+            view: UniverseView::default(),
+            spatial: SpatialBundle::from_transform(Transform::from_translation(offset.extend(0.))),
+        }
+    }
+}
+
+pub struct UniversePlugin<const S: usize>;
+
+impl<const S: usize> Plugin for UniversePlugin<S> {
+    fn build(&self, app: &mut App) {
+        app.add_event::<Intervention>()
+            .add_event::<ConfigReloaded>()
+            .add_system(forward_to_universes::<S>.before("step_universes"))
+            .add_system(step_universes::<S>.label("step_universes"))
+            .add_system(draw_universes::<S>.after("step_universes"));
+    }
+}
+
+// Tools used on a universe act on it, and every universe follows config reloads. The rules and
+// settings of a universe stay its own
+fn forward_to_universes<const S: usize>(
+    mut interventions: EventReader<Intervention>,
+    mut reloads: EventReader<ConfigReloaded>,
+    mut universe_query: Query<(&mut Universe<S>, &Transform)>,
+) {
+    let interventions: Vec<&Intervention> = interventions.iter().collect();
+    let reloads: Vec<&ConfigReloaded> = reloads.iter().collect();
+
+    for (mut universe, transform) in universe_query.iter_mut() {
+        let offset = transform.translation.truncate();
+        let bounds = universe.size().bounds();
+        let inside = |position: &[f32; 2]| {
+            let position = Vec2::from(*position) - offset;
+            position
+                .abs()
+                .cmple(bounds)
+                .all()
+                .then_some(position.to_array())
+        };
+
+        for intervention in &interventions {
+            let forwarded = match intervention {
+                Intervention::Force {
+                    position,
+                    radius,
+                    strength,
+                } => inside(position).map(|position| Intervention::Force {
+                    position,
+                    radius: *radius,
+                    strength: *strength,
+                }),
+                Intervention::Spawn {
+                    position,
+                    group,
+                    count,
+                } => inside(position).map(|position| Intervention::Spawn {
+                    position,
+                    group: *group,
+                    count: *count,
+                }),
+                Intervention::SetRules { .. } | Intervention::SetSettings(_) => None,
+            };
+            if let Some(intervention) = forwarded {
+                universe
+                    .world
+                    .resource_mut::<Events<Intervention>>()
+                    .send(intervention);
+            }
+        }
+
+        for ConfigReloaded(config) in &reloads {
+            universe
+                .world
+                .resource_mut::<Events<ConfigReloaded>>()
+                .send(ConfigReloaded(config.clone()));
+        }
+    }
+}
+
+fn step_universes<const S: usize>(mut universe_query: Query<&mut Universe<S>>) {
+    for mut universe in universe_query.iter_mut() {
+        universe.step();
+    }
+}
+
+type UniverseViewQuery<'w, 's, const S: usize> =
+    Query<'w, 's, (Entity, &'static mut Universe<S>, &'static mut UniverseView)>;
+
+fn draw_universes<const S: usize>(
+    mut commands: Commands,
+    (mut meshes, mut materials): (ResMut<Assets<Mesh>>, ResMut<Assets<ColorMaterial>>),
+    mut universe_query: UniverseViewQuery<S>,
+    mut view_query: Query<(&mut Transform, &mut Handle<ColorMaterial>), Without<Universe<S>>>,
+) {
+    for (universe_entity, mut universe, mut view) in universe_query.iter_mut() {
+        // New rules bring new colors
+        let colors = universe.rules().colors;
+        if !view.materials.iter().map(|(color, _)| *color).eq(colors) {
+            view.materials = colors
+                .iter()
+                .map(|color| (*color, materials.add(ColorMaterial::from(*color))))
+                .collect();
+        }
+        let mesh = view
+            .mesh
+            .get_or_insert_with(|| {
+                meshes.add(Mesh::from(shape::Circle {
+                    radius: 2.0,
+                    vertices: 32usize,
+                }))
+            })
+            .clone();
+
+        let mut views = HashMap::with_capacity(view.views.len());
+        for (particle, pos, _, group) in universe.particles() {
+            let material = view.materials[group].1.clone();
+            let translation = pos.extend(0.);
+
+            let entity = match view.views.remove(&particle) {
+                Some(entity) => {
+                    if let Ok((mut transform, mut handle)) = view_query.get_mut(entity) {
+                        transform.translation = translation;
+                        if *handle != material {
+                            *handle = material;
+                        }
+                    }
+                    entity
+                }
+                None => {
+                    let entity = commands
+                        .spawn(MaterialMesh2dBundle {
+                            mesh: mesh.clone().into(),
+                            material,
+                            transform: Transform::from_translation(translation),
+                            ..default()
+                        })
+                        .id();
+                    commands.entity(universe_entity).add_child(entity);
+                    entity
+                }
+            };
+            views.insert(particle, entity);
+        }
+
+        // Particles that died, or were replaced by new rules
+        for (_, entity) in view.views.drain() {
+            commands.entity(entity).despawn_recursive();
+        }
+        view.views = views;
+    }
+}